@@ -1,19 +1,64 @@
 #![no_std]
-use rtt_target::UpChannel;
+#[cfg(feature = "commands")]
+use rtt_target::DownChannel;
+use rtt_target::{ChannelMode, UpChannel};
 
 pub use rtt_target;
 
 static mut RTT_CHANNEL: Option<UpChannel> = None;
+#[cfg(feature = "commands")]
+static mut RTT_COMMAND_CHANNEL: Option<DownChannel> = None;
 
 pub fn init(channel: UpChannel) {
     unsafe { RTT_CHANNEL = Some(channel) };
 }
 
+// In NoBlockTrim mode, the channel might take a part of a packet. utrace sends
+// its rest on the next calls. In NoBlockSkip mode, a write, which wraps around
+// the channel buffer and does not fit, returns the length of its first part, but
+// nothing is committed, so it is reported as not accepted at all.
 #[utrace_macros::default_transport]
-pub fn write(buf: &[u8]) {
+pub fn write(buf: &[u8]) -> usize {
     unsafe {
         if let Some(ref mut channel) = RTT_CHANNEL {
-            channel.write(buf);
+            let written = channel.write(buf);
+            match channel.mode() {
+                ChannelMode::NoBlockSkip if written != buf.len() => 0,
+                _ => written,
+            }
+        } else {
+            0
         }
     }
 }
+
+/// Sets the down channel, on which the host sends commands. With OpenOCD and
+/// probe-rs, data written to the TCP connection goes to the down channel with
+/// the same number as the up channel, so it is usually channel 0.
+#[cfg(feature = "commands")]
+pub fn init_commands(channel: DownChannel) {
+    unsafe { RTT_COMMAND_CHANNEL = Some(channel) };
+}
+
+/// Reads the commands, sent by the host, and applies them. Call it periodically,
+/// for example from idle loop or a low-priority task, but from a single context only.
+#[cfg(feature = "commands")]
+pub fn poll_commands() {
+    let mut buf = [0; 16];
+
+    loop {
+        let read = unsafe {
+            if let Some(ref mut channel) = RTT_COMMAND_CHANNEL {
+                channel.read(&mut buf)
+            } else {
+                0
+            }
+        };
+
+        if read == 0 {
+            break;
+        }
+
+        utrace::process_commands(&buf[..read]);
+    }
+}
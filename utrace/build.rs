@@ -1,12 +1,71 @@
-use std::{env, error::Error, fs, path::PathBuf};
-
-fn main() -> Result<(), Box<dyn Error>> {
-    let linker_script = fs::read_to_string("utrace_linker.x.in")?;
-
-    let out = &PathBuf::from(env::var("OUT_DIR")?);
-    fs::write(out.join("utrace_linker.x"), linker_script)?;
-
-    println!("cargo:rustc-link-search={}", out.display());
-
-    Ok(())
-}
+use std::{env, error::Error, fs, path::PathBuf};
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let linker_script = fs::read_to_string("utrace_linker.x.in")?;
+
+    let out = &PathBuf::from(env::var("OUT_DIR")?);
+    fs::write(out.join("utrace_linker.x"), linker_script)?;
+
+    let sequence_interval = env_or("UTRACE_SEQUENCE_INTERVAL", 32)?;
+    if !(1..16384).contains(&sequence_interval) {
+        return Err("UTRACE_SEQUENCE_INTERVAL should be in range 1..16384".into());
+    }
+
+    let resync_interval = match env_or("UTRACE_RESYNC_INTERVAL", 0)? {
+        0 => "None".to_owned(),
+        n if n <= u32::MAX as u64 => format!("Some({n})"),
+        _ => return Err("UTRACE_RESYNC_INTERVAL is too large".into()),
+    };
+
+    // Without multicore feature, everything runs on the core 0
+    let max_cores = if env::var_os("CARGO_FEATURE_MULTICORE").is_some() {
+        env_or("UTRACE_MAX_CORES", 2)?
+    } else {
+        1
+    };
+    if !(1..=256).contains(&max_cores) {
+        return Err("UTRACE_MAX_CORES should be in range 1..=256".into());
+    }
+
+    let buffer_size = env_or("UTRACE_BUFFER_SIZE", 1024)?;
+    if !buffer_size.is_power_of_two() {
+        return Err("UTRACE_BUFFER_SIZE should be a power of two".into());
+    }
+
+    // Trace points with larger ids are always enabled
+    let enable_bitmap_size = env_or("UTRACE_ENABLE_BITMAP_SIZE", 256)?;
+    if enable_bitmap_size > 65536 {
+        return Err("UTRACE_ENABLE_BITMAP_SIZE should not exceed 65536".into());
+    }
+    let enable_bitmap_words = enable_bitmap_size.div_ceil(32);
+
+    let skip_overrides = env_or("UTRACE_SKIP_OVERRIDES", 8)?;
+
+    fs::write(
+        out.join("config.rs"),
+        format!(
+            "pub(crate) const SEQUENCE_INTERVAL: u16 = {sequence_interval};\n\
+             pub(crate) const RESYNC_INTERVAL: Option<u32> = {resync_interval};\n\
+             pub(crate) const MAX_CORES: usize = {max_cores};\n\
+             pub(crate) const BUFFER_SIZE: usize = {buffer_size};\n\
+             pub(crate) const ENABLE_BITMAP_WORDS: usize = {enable_bitmap_words};\n\
+             pub(crate) const SKIP_OVERRIDES: usize = {skip_overrides};\n"
+        ),
+    )?;
+
+    println!("cargo:rustc-link-search={}", out.display());
+    println!("cargo:rerun-if-changed=utrace_linker.x.in");
+
+    Ok(())
+}
+
+fn env_or(name: &str, default: u64) -> Result<u64, Box<dyn Error>> {
+    println!("cargo:rerun-if-env-changed={name}");
+
+    match env::var(name) {
+        Ok(v) => Ok(v
+            .parse()
+            .map_err(|_| format!("{name} should be a number, got {v:?}"))?),
+        Err(_) => Ok(default),
+    }
+}
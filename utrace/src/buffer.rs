@@ -0,0 +1,424 @@
+// Ring buffer of `buffered` feature.
+//
+// Trace points are written into it from critical sections, so there is a single
+// producer at a time. The only consumer is flush(), which runs outside of critical
+// sections and sends the data to the transport. Only atomic loads and stores are
+// used, so that it works on the cores without compare-and-swap instructions.
+//
+// With `flight_recorder` feature, the oldest trace points are overwritten instead,
+// and the buffer is only read by dump(), which runs in a critical section. The
+// overwritten packets are decoded to keep track of the state they established
+// (timestamps and the current core), which dump() sends before the buffered data.
+
+#[cfg(not(feature = "flight_recorder"))]
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::config::BUFFER_SIZE;
+
+// The buffer and its state are exported with fixed names, so that utrace_parser
+// can find them in a memory dump
+#[export_name = "__utrace_buffer"]
+static mut BUFFER: [u8; BUFFER_SIZE] = [0; BUFFER_SIZE];
+
+// Total number of bytes, written to and read from the buffer. They wrap around,
+// which is fine because BUFFER_SIZE is a power of two.
+#[export_name = "__utrace_buffer_write_pos"]
+static WRITE_POS: AtomicUsize = AtomicUsize::new(0);
+#[export_name = "__utrace_buffer_read_pos"]
+static READ_POS: AtomicUsize = AtomicUsize::new(0);
+
+#[cfg(not(feature = "flight_recorder"))]
+static FLUSHING: AtomicBool = AtomicBool::new(false);
+
+/// Puts the whole buf into the buffer and returns its length, or returns 0 if
+/// it does not fit. With `flight_recorder` feature, the oldest trace points
+/// are removed to make room for it.
+/// Must be called from a critical section
+pub(crate) fn write(buf: &[u8]) -> usize {
+    let write_pos = WRITE_POS.load(Ordering::Relaxed);
+    let read_pos = READ_POS.load(Ordering::Acquire);
+    let free = BUFFER_SIZE - write_pos.wrapping_sub(read_pos);
+
+    if free < buf.len() {
+        #[cfg(not(feature = "flight_recorder"))]
+        return 0;
+
+        #[cfg(feature = "flight_recorder")]
+        if buf.len() > BUFFER_SIZE || !recorder::evict(write_pos, buf.len() - free) {
+            return 0;
+        }
+    }
+
+    let start = write_pos % BUFFER_SIZE;
+    let first = buf.len().min(BUFFER_SIZE - start);
+    let buffer = core::ptr::addr_of_mut!(BUFFER) as *mut u8;
+
+    unsafe {
+        core::ptr::copy_nonoverlapping(buf.as_ptr(), buffer.add(start), first);
+        core::ptr::copy_nonoverlapping(buf.as_ptr().add(first), buffer, buf.len() - first);
+    }
+
+    WRITE_POS.store(write_pos.wrapping_add(buf.len()), Ordering::Release);
+
+    buf.len()
+}
+
+/// Sends the buffered data to the transport, until the transport is full.
+/// Returns the number of bytes sent
+#[cfg(not(feature = "flight_recorder"))]
+pub(crate) fn flush() -> usize {
+    let busy = critical_section::with(|_| {
+        let busy = FLUSHING.load(Ordering::Relaxed);
+        FLUSHING.store(true, Ordering::Relaxed);
+        busy
+    });
+
+    // Another flush is in progress, which was interrupted by this one
+    if busy {
+        return 0;
+    }
+
+    let sent = send();
+    FLUSHING.store(false, Ordering::Release);
+
+    sent
+}
+
+/// Retries sending the buffered data until the transport accepts all of it. Used
+/// on the panic path, which might have interrupted a flush, so it does not wait
+/// for the flush to finish. Must be called from a critical section
+#[cfg(not(feature = "flight_recorder"))]
+pub(crate) fn drain() {
+    while READ_POS.load(Ordering::Relaxed) != WRITE_POS.load(Ordering::Relaxed) {
+        send();
+    }
+}
+
+// Sends the buffered data, until the transport is full. Returns the number of bytes sent
+#[cfg(not(feature = "flight_recorder"))]
+fn send() -> usize {
+    // Data written during the flush is left for the next one, so that
+    // a fast producer can't keep us here forever
+    let end = WRITE_POS.load(Ordering::Acquire);
+    let start_pos = READ_POS.load(Ordering::Relaxed);
+    let mut read_pos = start_pos;
+
+    while read_pos != end {
+        let start = read_pos % BUFFER_SIZE;
+        let len = end.wrapping_sub(read_pos).min(BUFFER_SIZE - start);
+        let chunk = unsafe {
+            core::slice::from_raw_parts((core::ptr::addr_of!(BUFFER) as *const u8).add(start), len)
+        };
+
+        let written = crate::globals::transport_write(chunk).min(len);
+        read_pos = read_pos.wrapping_add(written);
+        READ_POS.store(read_pos, Ordering::Release);
+
+        if written < len {
+            break;
+        }
+    }
+
+    read_pos.wrapping_sub(start_pos)
+}
+
+#[cfg(feature = "flight_recorder")]
+pub(crate) use recorder::dump;
+
+#[cfg(feature = "flight_recorder")]
+mod recorder {
+    use core::sync::atomic::Ordering;
+
+    use utrace_core::encoding::{self, Decoder, Packet};
+
+    use super::{BUFFER, BUFFER_SIZE, READ_POS, WRITE_POS};
+    use crate::config::MAX_CORES;
+
+    // Decoder of the packets, which are removed from the buffer
+    struct Head {
+        decoder: Decoder,
+        // The last removed byte does not finish a packet
+        in_packet: bool,
+        // The removed packets include values, an instance or a timestamp of an
+        // event, whose trace point is still in the buffer
+        in_event: bool,
+    }
+
+    static mut HEAD: Head = Head {
+        decoder: Decoder::new(),
+        in_packet: false,
+        in_event: false,
+    };
+
+    // State, established by the removed packets
+    #[export_name = "__utrace_buffer_head_core"]
+    static mut HEAD_CORE: u8 = 0;
+    #[export_name = "__utrace_buffer_head_timestamps"]
+    static mut HEAD_TIMESTAMPS: [u64; MAX_CORES] = [0; MAX_CORES];
+
+    // Must be called from a critical section
+    fn remove_byte(read_pos: usize) -> u8 {
+        let byte = unsafe { (*core::ptr::addr_of!(BUFFER))[read_pos % BUFFER_SIZE] };
+        let head = unsafe { &mut *core::ptr::addr_of_mut!(HEAD) };
+        let head_core = unsafe { &mut *core::ptr::addr_of_mut!(HEAD_CORE) };
+        let timestamps = unsafe { &mut *core::ptr::addr_of_mut!(HEAD_TIMESTAMPS) };
+
+        if let Some(packet) = head.decoder.push_byte(byte) {
+            // Without multicore feature, MAX_CORES is 1
+            #[allow(clippy::modulo_one)]
+            let core = *head_core as usize % MAX_CORES;
+            head.in_packet = false;
+
+            // Absolute timestamp of an event is sent between its values and its trace point
+            match packet {
+                Packet::Reset(_) => *head_core = 0,
+                Packet::Core(core) => *head_core = core,
+                Packet::Timestamp(timestamp) => timestamps[core] = timestamp,
+                Packet::TracePoint(encoding::TracePoint { delta_t, .. })
+                | Packet::Marker { delta_t, .. }
+                | Packet::TaskSwitch { delta_t, .. } => {
+                    timestamps[core] = timestamps[core].wrapping_add(delta_t as u64);
+                    head.in_event = false;
+                }
+                Packet::Value(_) | Packet::Instance(_) => head.in_event = true,
+                _ => (),
+            }
+        } else {
+            head.in_packet = true;
+        }
+
+        READ_POS.store(read_pos.wrapping_add(1), Ordering::Release);
+
+        byte
+    }
+
+    // Returns true if the oldest byte in the buffer belongs to a partially removed
+    // event. Must be called from a critical section
+    fn in_event() -> bool {
+        let head = unsafe { &*core::ptr::addr_of!(HEAD) };
+        head.in_packet || head.in_event
+    }
+
+    /// Removes the oldest trace points, so that at least `len` bytes are free.
+    /// Values are removed together with the trace point they belong to.
+    /// Must be called from a critical section
+    pub(super) fn evict(write_pos: usize, len: usize) -> bool {
+        let mut read_pos = READ_POS.load(Ordering::Relaxed);
+        let mut freed = 0;
+
+        while read_pos != write_pos && (freed < len || in_event()) {
+            remove_byte(read_pos);
+            read_pos = read_pos.wrapping_add(1);
+            freed += 1;
+        }
+
+        freed >= len
+    }
+
+    // Sends as much of buf, as the transport accepts
+    fn send(mut buf: &[u8]) -> bool {
+        while !buf.is_empty() {
+            let written = crate::globals::transport_write(buf).min(buf.len());
+            if written == 0 {
+                return false;
+            }
+            buf = &buf[written..];
+        }
+
+        true
+    }
+
+    #[cfg(test)]
+    pub(super) fn reset_head() {
+        unsafe {
+            HEAD = Head {
+                decoder: Decoder::new(),
+                in_packet: false,
+                in_event: false,
+            };
+            HEAD_CORE = 0;
+            HEAD_TIMESTAMPS = [0; MAX_CORES];
+        }
+    }
+
+    /// Sends the state, established by the overwritten packets, followed by the
+    /// buffered data. The buffer is empty afterwards, even if the transport
+    /// did not accept all of it.
+    pub(crate) fn dump() -> bool {
+        critical_section::with(|_| {
+            let head_core = unsafe { HEAD_CORE };
+            let timestamps = unsafe { HEAD_TIMESTAMPS };
+            let mut sent = encoding::encode_reset(crate::globals::session_header(), send)
+                && encoding::encode_build_id(crate::globals::build_id(), send);
+
+            for (core, timestamp) in timestamps.iter().enumerate() {
+                if cfg!(feature = "multicore") {
+                    sent = sent && encoding::encode_core(core as u8, send);
+                }
+                sent = sent && encoding::encode_timestamp(*timestamp, send);
+            }
+            if cfg!(feature = "multicore") {
+                sent = sent && encoding::encode_core(head_core, send);
+            }
+
+            let end = WRITE_POS.load(Ordering::Relaxed);
+            let mut read_pos = READ_POS.load(Ordering::Relaxed);
+            let mut chunk = [0; 64];
+
+            while read_pos != end {
+                let len = end.wrapping_sub(read_pos).min(chunk.len());
+                for byte in &mut chunk[..len] {
+                    *byte = remove_byte(read_pos);
+                    read_pos = read_pos.wrapping_add(1);
+                }
+                sent = sent && send(&chunk[..len]);
+            }
+
+            sent
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use utrace_core::encoding::{self, Packet, TracePoint, Value};
+
+    use super::*;
+    use crate::test_support;
+
+    // Empties the buffer, leaving its positions `offset` bytes before the end of it
+    fn reset(offset: usize) {
+        let pos = BUFFER_SIZE - offset;
+        READ_POS.store(pos, Ordering::Relaxed);
+        WRITE_POS.store(pos, Ordering::Relaxed);
+
+        #[cfg(feature = "flight_recorder")]
+        recorder::reset_head();
+    }
+
+    fn write(encoder: impl FnOnce(&mut dyn FnMut(&[u8]) -> bool) -> bool) -> bool {
+        critical_section::with(|_| encoder(&mut |buf| super::write(buf) == buf.len()))
+    }
+
+    #[cfg(not(feature = "flight_recorder"))]
+    #[test]
+    fn wrapped_write() {
+        let _lock = test_support::lock();
+        reset(2);
+
+        let tp = TracePoint {
+            id: 300,
+            delta_t: 1,
+        };
+        assert!(write(|w| encoding::encode(tp, w)));
+        assert!(write(|w| encoding::encode_value(Value::U32(7), w)));
+        assert_eq!(
+            flush(),
+            WRITE_POS.load(Ordering::Relaxed) - (BUFFER_SIZE - 2)
+        );
+
+        assert!(matches!(
+            test_support::decode(&test_support::take_stream())[..],
+            [
+                Packet::TracePoint(TracePoint {
+                    id: 300,
+                    delta_t: 1
+                }),
+                Packet::Value(Value::U32(7)),
+            ]
+        ));
+    }
+
+    #[cfg(not(feature = "flight_recorder"))]
+    #[test]
+    fn full_buffer_and_transport() {
+        let _lock = test_support::lock();
+        reset(0);
+
+        assert_eq!(
+            critical_section::with(|_| super::write(&[0; BUFFER_SIZE + 1])),
+            0
+        );
+        assert_eq!(critical_section::with(|_| super::write(&[1, 2, 3])), 3);
+
+        // The rest is sent by the next flush
+        test_support::set_accept(2);
+        assert_eq!(flush(), 2);
+        test_support::set_accept(usize::MAX);
+        assert_eq!(flush(), 1);
+        assert_eq!(test_support::take_stream(), [1, 2, 3]);
+    }
+
+    #[cfg(not(feature = "flight_recorder"))]
+    #[test]
+    fn drain_during_flush() {
+        let _lock = test_support::lock();
+        reset(0);
+
+        assert_eq!(critical_section::with(|_| super::write(&[1, 2, 3])), 3);
+
+        // A panic has interrupted a flush
+        FLUSHING.store(true, Ordering::Relaxed);
+        assert_eq!(flush(), 0);
+        test_support::set_accept(1);
+        critical_section::with(|_| drain());
+        FLUSHING.store(false, Ordering::Relaxed);
+
+        assert_eq!(test_support::take_stream(), [1, 2, 3]);
+    }
+
+    #[cfg(feature = "flight_recorder")]
+    #[test]
+    fn eviction_keeps_events_whole() {
+        let _lock = test_support::lock();
+        reset(5);
+
+        // Values with their trace points take more than the whole buffer. Every
+        // other event carries an absolute timestamp between its value and its
+        // trace point, as if a resync was due.
+        let events = BUFFER_SIZE;
+        for i in 0..events {
+            let absolute = i % 2 == 1;
+            let tp = TracePoint {
+                id: 300,
+                delta_t: if absolute { 0 } else { 10 },
+            };
+            assert!(write(|w| encoding::encode_value(Value::U32(i as u32), w)));
+            if absolute {
+                let timestamp = 10 * (i as u64 + 1);
+                assert!(write(|w| encoding::encode_timestamp(timestamp, w)));
+            }
+            assert!(write(|w| encoding::encode(tp, w)));
+        }
+
+        assert!(dump());
+        let packets = test_support::decode(&test_support::take_stream());
+        assert_eq!(
+            READ_POS.load(Ordering::Relaxed),
+            WRITE_POS.load(Ordering::Relaxed)
+        );
+
+        // The first remaining event starts with its value
+        let first = packets
+            .iter()
+            .position(|p| matches!(p, Packet::Value(_) | Packet::TracePoint(_)))
+            .unwrap();
+        let Packet::Value(Value::U32(first_event)) = packets[first] else {
+            panic!("Event without its value: {:?}", packets[first]);
+        };
+        let points = packets[first..]
+            .iter()
+            .filter(|p| matches!(p, Packet::TracePoint(_)))
+            .count();
+        assert_eq!(first_event as usize + points, events);
+
+        // Timestamp of the head accounts for the evicted trace points. It is sent
+        // for the core 0 first, if there are several cores
+        let head_timestamp = packets[..first].iter().find_map(|p| match p {
+            Packet::Timestamp(timestamp) => Some(*timestamp),
+            _ => None,
+        });
+        assert_eq!(head_timestamp, Some(10 * first_event as u64));
+    }
+}
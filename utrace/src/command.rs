@@ -0,0 +1,173 @@
+// Handler of `commands` feature, which applies commands sent by the host.
+
+use utrace_core::command::{Command, CommandDecoder};
+
+use crate::config::SKIP_OVERRIDES;
+
+static mut DECODER: CommandDecoder = CommandDecoder::new();
+
+// Skip limits, set by the host, as (trace point id, limit). Id 0 is never
+// assigned to a trace point and marks a free slot.
+static mut SKIP_LIMITS: [(u16, u32); SKIP_OVERRIDES] = [(0, 0); SKIP_OVERRIDES];
+
+/// Decodes commands from the data, received from the host, and applies them.
+/// A command might be split between calls.
+pub(crate) fn process(data: &[u8]) {
+    for byte in data {
+        let command = critical_section::with(|_| unsafe {
+            (*core::ptr::addr_of_mut!(DECODER)).push_byte(*byte)
+        });
+
+        if let Some(command) = command {
+            apply(command);
+        }
+    }
+}
+
+fn apply(command: Command) {
+    match command {
+        Command::SetEnabled(enabled) => crate::globals::set_enabled(enabled),
+        Command::SetTracePointEnabled { id, enabled } => {
+            crate::globals::set_point_enabled(id, enabled);
+        }
+        Command::SetSkip { id, limit } => set_skip_limit(id, limit),
+        Command::Init => crate::init(),
+        Command::Resync => critical_section::with(|_| crate::globals::default_timestamp_resync()),
+    }
+}
+
+// Limit 0 restores the limit, set at compile time. If the table is full, the
+// command is ignored.
+fn set_skip_limit(id: u16, limit: u32) {
+    if id == 0 {
+        return;
+    }
+
+    critical_section::with(|_| {
+        let limits = unsafe { &mut *core::ptr::addr_of_mut!(SKIP_LIMITS) };
+
+        if let Some(slot) = limits.iter_mut().find(|(slot_id, _)| *slot_id == id) {
+            *slot = if limit == 0 { (0, 0) } else { (id, limit) };
+        } else if limit != 0 {
+            if let Some(slot) = limits.iter_mut().find(|(slot_id, _)| *slot_id == 0) {
+                *slot = (id, limit);
+            }
+        }
+    });
+}
+
+/// Returns the skip limit of a trace point, if it was set by the host
+pub(crate) fn skip_limit(id: u16) -> Option<u32> {
+    // Free slots have id 0
+    if id == 0 {
+        return None;
+    }
+
+    critical_section::with(|_| {
+        let limits = unsafe { &*core::ptr::addr_of!(SKIP_LIMITS) };
+
+        limits
+            .iter()
+            .find(|(slot_id, _)| *slot_id == id)
+            .map(|(_, limit)| *limit)
+    })
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+
+    use std::vec::Vec;
+
+    use utrace_core::command::encode_command;
+
+    use super::*;
+    use crate::test_support;
+
+    fn encode(commands: &[Command]) -> Vec<u8> {
+        let mut data = Vec::new();
+        for command in commands {
+            encode_command(*command, |b| data.extend_from_slice(b));
+        }
+
+        data
+    }
+
+    #[test]
+    fn skip_limits() {
+        let _lock = test_support::lock();
+
+        // A command might be split between calls
+        for byte in encode(&[Command::SetSkip { id: 7, limit: 300 }]) {
+            process(&[byte]);
+        }
+        assert_eq!(skip_limit(7), Some(300));
+
+        let ids = 100..100 + SKIP_OVERRIDES as u16;
+        let commands: Vec<_> = ids
+            .clone()
+            .map(|id| Command::SetSkip { id, limit: 2 })
+            .collect();
+        process(&encode(&commands));
+        // The table is full, the last trace point keeps its limit
+        assert!(ids
+            .clone()
+            .take(SKIP_OVERRIDES - 1)
+            .all(|id| skip_limit(id) == Some(2)));
+        assert_eq!(skip_limit(ids.end - 1), None);
+
+        // Limit 0 frees the slot
+        process(&encode(&[
+            Command::SetSkip { id: 7, limit: 0 },
+            Command::SetSkip { id: 0, limit: 5 },
+        ]));
+        assert_eq!(skip_limit(7), None);
+        assert_eq!(skip_limit(0), None);
+
+        let commands: Vec<_> = ids.map(|id| Command::SetSkip { id, limit: 0 }).collect();
+        process(&encode(&commands));
+    }
+
+    #[test]
+    fn enable_and_disable() {
+        let _lock = test_support::lock();
+
+        process(&encode(&[Command::SetTracePointEnabled {
+            id: 9,
+            enabled: false,
+        }]));
+        assert!(!crate::globals::is_point_enabled(9));
+        assert!(crate::globals::is_point_enabled(10));
+
+        process(&encode(&[
+            Command::SetTracePointEnabled {
+                id: 9,
+                enabled: true,
+            },
+            Command::SetEnabled(false),
+        ]));
+        assert!(!crate::globals::is_enabled());
+        process(&encode(&[Command::SetEnabled(true)]));
+        assert!(crate::globals::is_point_enabled(9));
+    }
+
+    #[cfg(not(feature = "buffered"))]
+    #[test]
+    fn init_and_resync() {
+        use utrace_core::encoding::Packet;
+
+        let _lock = test_support::lock();
+
+        process(&encode(&[Command::Init]));
+        let packets = test_support::decode(&test_support::take_stream());
+        assert!(matches!(packets[0], Packet::Reset(_)));
+
+        process(&encode(&[Command::Resync]));
+        crate::marker(1);
+        let packets = test_support::decode(&test_support::take_stream());
+        assert!(matches!(
+            packets[..],
+            [.., Packet::Timestamp(_), Packet::Marker { code: 1, .. }]
+        ));
+    }
+}
@@ -1,27 +1,586 @@
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+use utrace_core::build_id;
+use utrace_core::encoding::{self, SessionHeader};
+
+use crate::tracer::BuildIdEntry;
+
 // Spurious (?) dead_code warning
 #[allow(dead_code)]
-pub(crate) fn default_write(buf: &[u8]) {
+pub(crate) fn transport_write(buf: &[u8]) -> usize {
     extern "Rust" {
-        fn __utrace_default_transport_write(buf: &[u8]);
+        fn __utrace_default_transport_write(buf: &[u8]) -> usize;
     }
 
-    unsafe {
-        __utrace_default_transport_write(buf);
+    unsafe { __utrace_default_transport_write(buf) }
+}
+
+// Rest of the packet, which was accepted by the transport only partially
+#[cfg(not(feature = "buffered"))]
+struct Owed {
+    buf: [u8; encoding::MAX_PACKET_SIZE],
+    start: usize,
+    end: usize,
+}
+
+#[cfg(not(feature = "buffered"))]
+static mut OWED: Owed = Owed {
+    buf: [0; encoding::MAX_PACKET_SIZE],
+    start: 0,
+    end: 0,
+};
+
+/// Passes the data to the transport, or, with `buffered` feature, to the ring buffer.
+/// A packet, which is accepted by the transport partially, is counted as sent, and
+/// its rest is sent before anything else, so that the receiver does not join it
+/// to the next packet. Must be called from a critical section
+#[cfg(not(feature = "buffered"))]
+pub(crate) fn default_write(buf: &[u8]) -> usize {
+    if !send_owed() {
+        return 0;
     }
+
+    let written = transport_write(buf).min(buf.len());
+    if written == 0 {
+        return 0;
+    }
+
+    let owed = unsafe { &mut *core::ptr::addr_of_mut!(OWED) };
+    let rest = &buf[written..];
+    owed.buf[..rest.len()].copy_from_slice(rest);
+    owed.start = 0;
+    owed.end = rest.len();
+
+    buf.len()
 }
 
-#[allow(dead_code)]
-pub(crate) fn default_timestamp_delta() -> u32 {
+// Sends the rest of the partially accepted packet, returns false if the transport
+// is full before all of it is sent. Must be called from a critical section
+#[cfg(not(feature = "buffered"))]
+fn send_owed() -> bool {
+    let owed = unsafe { &mut *core::ptr::addr_of_mut!(OWED) };
+
+    while owed.start != owed.end {
+        let written = transport_write(&owed.buf[owed.start..owed.end]).min(owed.end - owed.start);
+        if written == 0 {
+            return false;
+        }
+        owed.start += written;
+    }
+
+    true
+}
+
+/// Retries the rest of the partially accepted packet until the transport takes
+/// all of it. Used on the panic path, where nothing else would send it.
+/// Must be called from a critical section
+#[cfg(not(feature = "buffered"))]
+pub(crate) fn drain() {
+    while !send_owed() {}
+}
+
+#[cfg(feature = "buffered")]
+pub(crate) fn default_write(buf: &[u8]) -> usize {
+    crate::buffer::write(buf)
+}
+
+/// Returns true if the whole buffer was accepted by the transport
+pub(crate) fn default_write_all(buf: &[u8]) -> bool {
+    default_write(buf) == buf.len()
+}
+
+/// Replaces the value of the word with `f` of it and returns the previous one. The word
+/// might be updated from interrupt handlers and other cores, and read-modify-write
+/// atomics are not available on all targets, so it is done in a critical section
+pub(crate) fn update(word: &AtomicU32, f: impl FnOnce(u32) -> u32) -> u32 {
+    critical_section::with(|_| {
+        let value = word.load(Ordering::Relaxed);
+        word.store(f(value), Ordering::Relaxed);
+        value
+    })
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(true);
+
+// A set bit disables the respective trace point, so that all trace points
+// are enabled after zero-initialization
+#[allow(clippy::declare_interior_mutable_const)]
+const DISABLED_WORD_INIT: AtomicU32 = AtomicU32::new(0);
+
+static DISABLED_POINTS: [AtomicU32; crate::config::ENABLE_BITMAP_WORDS] =
+    [DISABLED_WORD_INIT; crate::config::ENABLE_BITMAP_WORDS];
+
+pub(crate) fn set_enabled(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+pub(crate) fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Trace points, which are not covered by the bitmap, can not be disabled individually,
+/// false is returned for them
+pub(crate) fn set_point_enabled(id: u16, enabled: bool) -> bool {
+    let Some(word) = DISABLED_POINTS.get(id as usize / 32) else {
+        return false;
+    };
+    let mask = 1 << (id % 32);
+
+    update(
+        word,
+        |bits| if enabled { bits & !mask } else { bits | mask },
+    );
+
+    true
+}
+
+static NEXT_INSTANCE: AtomicU32 = AtomicU32::new(0);
+
+/// Allocates an id for a new instance of a traced async fn
+pub(crate) fn next_instance() -> u32 {
+    update(&NEXT_INSTANCE, |instance| instance.wrapping_add(1))
+}
+
+/// Returns false if tracing or the trace point itself is disabled
+pub(crate) fn is_point_enabled(id: u16) -> bool {
+    if !is_enabled() {
+        return false;
+    }
+
+    match DISABLED_POINTS.get(id as usize / 32) {
+        Some(word) => word.load(Ordering::Relaxed) & (1 << (id % 32)) == 0,
+        None => true,
+    }
+}
+
+/// Describes the encoding and the enabled features for the trace stream receiver
+pub(crate) fn session_header() -> SessionHeader {
+    let mut flags = 0;
+
+    if cfg!(feature = "sequence") {
+        flags |= encoding::SESSION_FLAG_SEQUENCE;
+    }
+    if cfg!(feature = "multicore") {
+        flags |= encoding::SESSION_FLAG_MULTICORE;
+    }
+    if cfg!(feature = "buffered") {
+        flags |= encoding::SESSION_FLAG_BUFFERED;
+    }
+    if cfg!(feature = "flight_recorder") {
+        flags |= encoding::SESSION_FLAG_FLIGHT_RECORDER;
+    }
+
+    SessionHeader {
+        flags,
+        ..Default::default()
+    }
+}
+
+/// Returns id of the core, which executes the caller, or None if it is not
+/// less than `UTRACE_MAX_CORES`, so that there is no state for it
+#[cfg(feature = "multicore")]
+pub(crate) fn current_core() -> Option<u8> {
+    extern "Rust" {
+        fn __utrace_core_id_function() -> u8;
+    }
+
+    let core = unsafe { __utrace_core_id_function() };
+    ((core as usize) < crate::config::MAX_CORES).then_some(core)
+}
+
+#[cfg(not(feature = "multicore"))]
+pub(crate) fn current_core() -> Option<u8> {
+    Some(0)
+}
+
+// Every core keeps its own reference time, so that trace points of one core
+// do not affect deltas of another one.
+#[derive(Clone, Copy)]
+struct TimestampState {
+    last: u64,
+    points_since_resync: u32,
+    // Absolute timestamp is requested by the host
+    resync: bool,
+}
+
+const TIMESTAMP_STATE_INIT: TimestampState = TimestampState {
+    last: 0,
+    points_since_resync: 0,
+    resync: false,
+};
+
+impl TimestampState {
+    // Moves the reference time to `now` and returns what should be sent for it
+    fn advance(&mut self, now: u64, resync_interval: Option<u32>) -> TimestampDelta {
+        let delta = now.wrapping_sub(self.last);
+        self.last = now;
+
+        let mut resync = core::mem::take(&mut self.resync);
+        if let Some(interval) = resync_interval {
+            self.points_since_resync += 1;
+            if self.points_since_resync >= interval {
+                self.points_since_resync = 0;
+                resync = true;
+            }
+        }
+
+        match u32::try_from(delta) {
+            Ok(delta) if !resync => TimestampDelta::Relative(delta),
+            _ => TimestampDelta::Absolute(now),
+        }
+    }
+}
+
+static mut TIMESTAMP_STATE: [TimestampState; crate::config::MAX_CORES] =
+    [TIMESTAMP_STATE_INIT; crate::config::MAX_CORES];
+// State before the last delta of every core, restored if its trace point is not sent
+static mut PREV_TIMESTAMP_STATE: [TimestampState; crate::config::MAX_CORES] =
+    [TIMESTAMP_STATE_INIT; crate::config::MAX_CORES];
+
+/// Current time, as returned by the function, annotated with #[utrace::timestamp]
+pub(crate) fn timestamp() -> u64 {
     extern "Rust" {
         fn __utrace_timestamp_function() -> u64;
     }
 
-    static mut LAST_TIMESTAMP: u64 = 0;
+    unsafe { __utrace_timestamp_function() }
+}
 
-    let current_timestamp = unsafe { __utrace_timestamp_function() };
+/// Number of timestamp ticks per second, declared with #[utrace::timestamp]
+pub(crate) fn timestamp_freq_hz() -> u64 {
+    extern "Rust" {
+        static __utrace_timestamp_freq_hz: u64;
+    }
 
-    let delta = (current_timestamp - unsafe { LAST_TIMESTAMP }) as u32;
-    unsafe { LAST_TIMESTAMP = current_timestamp };
+    unsafe { __utrace_timestamp_freq_hz }
+}
+
+pub(crate) enum TimestampDelta {
+    /// Delta to the previous timestamp
+    Relative(u32),
+    /// Delta does not fit or a periodic resync is due, absolute timestamp should be sent
+    Absolute(u64),
+}
+
+// Must be called from a critical section
+#[allow(dead_code)]
+pub(crate) fn default_timestamp_delta(core: u8) -> TimestampDelta {
+    let current_timestamp = timestamp();
+
+    let core = core as usize;
+    let mut state = unsafe { TIMESTAMP_STATE[core] };
+    unsafe { PREV_TIMESTAMP_STATE[core] = state };
+
+    let delta = state.advance(current_timestamp, crate::config::RESYNC_INTERVAL);
+    unsafe { TIMESTAMP_STATE[core] = state };
 
     delta
 }
+
+/// Establishes reference time for all cores and returns it.
+/// Must be called from a critical section
+pub(crate) fn default_timestamp_reset() -> u64 {
+    let current_timestamp = timestamp();
+
+    let state = TimestampState {
+        last: current_timestamp,
+        points_since_resync: 0,
+        resync: false,
+    };
+    unsafe {
+        TIMESTAMP_STATE = [state; crate::config::MAX_CORES];
+        PREV_TIMESTAMP_STATE = TIMESTAMP_STATE;
+    }
+
+    current_timestamp
+}
+
+/// Makes the next trace point of every core carry an absolute timestamp.
+/// Must be called from a critical section
+#[cfg(feature = "commands")]
+pub(crate) fn default_timestamp_resync() {
+    let states = unsafe { &mut *core::ptr::addr_of_mut!(TIMESTAMP_STATE) };
+    for state in states {
+        state.resync = true;
+    }
+}
+
+/// Takes back the last [default_timestamp_delta] call, so that its delta is
+/// accounted in the next one, and a resync, which was due, is still done.
+/// Used if the respective trace point was not sent.
+/// Must be called from a critical section
+pub(crate) fn default_timestamp_rollback(core: u8) {
+    let core = core as usize;
+    unsafe { TIMESTAMP_STATE[core] = PREV_TIMESTAMP_STATE[core] };
+}
+
+// Core, which has emitted the last packet
+#[cfg(feature = "multicore")]
+static mut LAST_CORE: u8 = 0;
+
+/// Sends reference time to the receiver for every core. Returns false if the
+/// transport is full. Must be called from a critical section
+#[cfg(feature = "multicore")]
+pub(crate) fn default_core_reset(timestamp: u64) -> bool {
+    unsafe { LAST_CORE = (crate::config::MAX_CORES - 1) as u8 };
+
+    (0..crate::config::MAX_CORES).all(|core| {
+        utrace_core::encoding::encode_core(core as u8, default_write_all)
+            && utrace_core::encoding::encode_timestamp(timestamp, default_write_all)
+    })
+}
+
+/// Sends a core packet, if the caller runs on another core than the previous
+/// trace point. Returns false if the transport is full.
+/// Must be called from a critical section
+#[cfg(feature = "multicore")]
+pub(crate) fn default_core_switch(core: u8) -> bool {
+    if core == unsafe { LAST_CORE } {
+        return true;
+    }
+
+    if utrace_core::encoding::encode_core(core, default_write_all) {
+        unsafe { LAST_CORE = core };
+        true
+    } else {
+        false
+    }
+}
+
+#[cfg(feature = "sequence")]
+static mut POINT_COUNTER: u16 = 0;
+
+// Must be called from a critical section
+#[cfg(feature = "sequence")]
+pub(crate) fn default_sequence_reset() {
+    unsafe { POINT_COUNTER = 0 };
+}
+
+// Must be called from a critical section
+#[cfg(feature = "sequence")]
+pub(crate) fn default_sequence_tick() {
+    let counter = unsafe { POINT_COUNTER }.wrapping_add(1);
+    unsafe { POINT_COUNTER = counter };
+
+    if counter % crate::config::SEQUENCE_INTERVAL == 0 {
+        utrace_core::encoding::encode_sequence(counter, default_write);
+    }
+}
+
+/// Computes build ID from the table, placed in the elf by trace point macros
+pub(crate) fn build_id() -> u32 {
+    extern "C" {
+        static __utrace_build_id_start: BuildIdEntry;
+        static __utrace_build_id_end: BuildIdEntry;
+    }
+
+    let start = core::ptr::addr_of!(__utrace_build_id_start);
+    let end = core::ptr::addr_of!(__utrace_build_id_end);
+    let len = (end as usize - start as usize) / core::mem::size_of::<BuildIdEntry>();
+    let entries = unsafe { core::slice::from_raw_parts(start, len) };
+
+    entries.iter().fold(0, |build_id, entry| {
+        let id = entry.id as *const u8 as u16;
+        build_id::combine(build_id, build_id::trace_point_hash(entry.hash, id))
+    })
+}
+
+#[cfg(all(test, not(feature = "buffered")))]
+mod test {
+    extern crate std;
+
+    use std::vec::Vec;
+
+    use utrace_core::encoding::{self, Packet, TracePoint};
+
+    use super::TimestampDelta;
+    use crate::test_support;
+
+    // Timestamps, markers and overflows, which were sent since the last call
+    fn sent_events() -> Vec<Packet> {
+        test_support::decode(&test_support::take_stream())
+            .into_iter()
+            .filter(|p| {
+                matches!(
+                    p,
+                    Packet::Timestamp(_) | Packet::Marker { .. } | Packet::Overflow(_)
+                )
+            })
+            .collect()
+    }
+
+    fn init(now: u64) {
+        test_support::set_time(now);
+        crate::init();
+        test_support::take_stream();
+    }
+
+    #[test]
+    fn enable_bitmap() {
+        let _lock = test_support::lock();
+
+        assert!(super::set_point_enabled(40, false));
+        assert!(!super::is_point_enabled(40));
+        assert!(super::is_point_enabled(41));
+        assert!(super::is_point_enabled(8));
+
+        // The global switch overrides the bitmap
+        super::set_enabled(false);
+        assert!(!super::is_point_enabled(41));
+        super::set_enabled(true);
+        assert!(super::is_point_enabled(41));
+
+        assert!(super::set_point_enabled(40, true));
+        assert!(super::is_point_enabled(40));
+
+        // Ids beyond the bitmap are always enabled
+        let id = (crate::config::ENABLE_BITMAP_WORDS * 32) as u16;
+        assert!(!super::set_point_enabled(id, false));
+        assert!(super::is_point_enabled(id));
+        assert!(!super::set_point_enabled(u16::MAX, false));
+        assert!(super::is_point_enabled(u16::MAX));
+    }
+
+    #[test]
+    fn delta_overflow() {
+        let _lock = test_support::lock();
+        init(100);
+
+        test_support::set_time(100 + (1 << 32));
+        crate::marker(1);
+        test_support::set_time(105 + (1 << 32));
+        crate::marker(2);
+
+        assert!(matches!(
+            sent_events()[..],
+            [
+                Packet::Timestamp(timestamp),
+                Packet::Marker {
+                    code: 1,
+                    delta_t: 0
+                },
+                Packet::Marker {
+                    code: 2,
+                    delta_t: 5
+                },
+            ] if timestamp == 100 + (1 << 32)
+        ));
+    }
+
+    #[test]
+    fn periodic_resync() {
+        let mut state = super::TIMESTAMP_STATE_INIT;
+        let deltas: Vec<_> = (1..=5).map(|i| state.advance(10 * i, Some(2))).collect();
+
+        assert!(matches!(
+            deltas[..],
+            [
+                TimestampDelta::Relative(10),
+                TimestampDelta::Absolute(20),
+                TimestampDelta::Relative(10),
+                TimestampDelta::Absolute(40),
+                TimestampDelta::Relative(10),
+            ]
+        ));
+    }
+
+    #[test]
+    fn dropped_timestamp() {
+        let _lock = test_support::lock();
+        init(100);
+
+        // The delta does not fit, but the transport is full
+        test_support::set_time(100 + (1 << 32));
+        test_support::set_accept(0);
+        crate::marker(1);
+        test_support::set_accept(usize::MAX);
+        test_support::set_time(105 + (1 << 32));
+        crate::marker(2);
+
+        assert!(matches!(
+            sent_events()[..],
+            [
+                Packet::Overflow(1),
+                Packet::Timestamp(timestamp),
+                Packet::Marker {
+                    code: 2,
+                    delta_t: 0
+                },
+            ] if timestamp == 105 + (1 << 32)
+        ));
+    }
+
+    #[cfg(feature = "commands")]
+    #[test]
+    fn dropped_resync() {
+        let _lock = test_support::lock();
+        init(100);
+
+        // Absolute timestamp, requested by the host, is sent with the next trace point
+        critical_section::with(|_| super::default_timestamp_resync());
+        test_support::set_time(110);
+        test_support::set_accept(0);
+        crate::marker(1);
+        test_support::set_accept(usize::MAX);
+        test_support::set_time(120);
+        crate::marker(2);
+
+        assert!(matches!(
+            sent_events()[..],
+            [
+                Packet::Overflow(1),
+                Packet::Timestamp(120),
+                Packet::Marker {
+                    code: 2,
+                    delta_t: 0
+                },
+            ]
+        ));
+    }
+
+    #[test]
+    fn drained_packet() {
+        let _lock = test_support::lock();
+
+        critical_section::with(|_| {
+            test_support::set_accept(1);
+            assert!(encoding::encode_value(
+                encoding::Value::U32(u32::MAX),
+                super::default_write_all
+            ));
+            // One byte per transport call
+            super::drain();
+        });
+
+        let packets = test_support::decode(&test_support::take_stream());
+        assert!(matches!(
+            packets[..],
+            [Packet::Value(encoding::Value::U32(u32::MAX))]
+        ));
+    }
+
+    #[test]
+    fn partially_accepted_packet() {
+        let _lock = test_support::lock();
+
+        critical_section::with(|_| {
+            let tp = |id| TracePoint { id, delta_t: 1 };
+            test_support::set_accept(1);
+            assert!(encoding::encode(tp(5), super::default_write_all));
+            test_support::set_accept(0);
+            assert!(!encoding::encode(tp(6), super::default_write_all));
+            // The rest of the first packet is sent before the next one
+            test_support::set_accept(usize::MAX);
+            assert!(encoding::encode(tp(7), super::default_write_all));
+        });
+
+        let packets = test_support::decode(&test_support::take_stream());
+        assert!(matches!(
+            packets[..],
+            [
+                Packet::TracePoint(TracePoint { id: 5, delta_t: 1 }),
+                Packet::TracePoint(TracePoint { id: 7, delta_t: 1 }),
+            ]
+        ));
+    }
+}
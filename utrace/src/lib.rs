@@ -28,9 +28,61 @@ fn do_something_else() {
 
 When [#\[trace\]](crate::trace) instruments an async function, the instants of the respective future creation, dropping and poll spans will be reported by default.
 
-<div class="warning">
-The current implementation assumes that traced Futures are not reentrant (it implies that only one instance of an instrumented async function is pending at each moment). If this is not the case, Future lifecycle tracking will be broken.
-</div>
+Every Future, created by an instrumented async function, gets an instance id, which is carried by its trace points, so
+several instances of the same function may be pending at the same time. *utrace-capture* draws each instance as a separate
+async track, where its poll spans are nested into its lifetime.
+
+Frequently called functions and spans can be sampled, so that they do not flood the transport. With `skip = N` attribute,
+only each Nth call is reported. With `min_interval_us = N`, a call is reported only if the previous reported one is at
+least N microseconds old, which suits functions called in bursts. The number of suppressed calls is shown with the next
+reported one. The interval is converted to timestamp ticks with the time base of the timestamp function, see below. For async functions,
+the sampling decision is made once per Future, when it is created, so that a sampled Future is reported along with all of
+its polls, and other Futures are not reported at all.
+
+```ignore
+#[utrace::trace(min_interval_us = 500)]
+fn on_rx_byte() {
+}
+
+{
+    utrace::trace_here!(comment = "burst", min_interval = 500);
+    ...
+}
+```
+
+Values, such as a queue depth or an error code, can be reported next to the spans with [trace_value]:
+
+```ignore
+utrace::trace_value!("queue_depth", queue.len() as u16);
+```
+
+Supported value types are `u8`, `u16`, `u32`, `i32` and `f32`. Signals like a buffer fill level or a battery voltage
+can be reported with [counter] in the same way; each of them is plotted as a separate counter track:
+
+```ignore
+utrace::counter!("battery_mv", battery_voltage_mv);
+```
+
+Log messages are reported with [log], which stores the format string in the elf file and sends only the arguments, so
+the messages are shown on the same timeline as the spans:
+
+```ignore
+utrace::log!("motor speed {} rpm", rpm);
+```
+
+A moment in the trace, for example a phase of a test, can be marked with [marker], which is shown across the whole trace.
+If an RTOS is used, call [task_switch] from its context switch hook, and the running tasks of every core are shown on
+a separate track.
+
+Interrupt handlers are instrumented with [#\[trace_isr\]](crate::trace_isr), which can also report the active priority
+level of the interrupt. *utrace-capture* draws the interrupts of every core on a separate track, where nested interrupts
+and the spans started by the handlers are nested into each other, so the spans they preempt stay intact:
+
+```ignore
+#[utrace::trace_isr(priority = 2u8)]
+fn UART0() {
+}
+```
 
 ## Trace information timestamping and transport
 While tracing instrumentation itself is platform-agnostic, it requires a way of obtaining timestamps and a channel for data transfer from dut to the host system.
@@ -46,17 +98,47 @@ fn utrace_timestamp_fn() -> u64 {
 
 In the current version, the signature of the timestamp function must be `rust fn() -> u64`.
 
+By default, timestamps are assumed to be in microseconds. If the timestamp function counts in other units, declare its
+time base, either as a frequency, like `#[utrace::timestamp(freq_hz = 64_000_000)]`, or as a unit, like
+`#[utrace::timestamp(unit = "ns")]`. It is recorded in the elf file, and *utrace-capture* converts timestamps accordingly.
+
+Trace points carry the difference to the previous timestamp. If it does not fit into 32 bits, for example after a long
+sleep, the absolute timestamp is sent instead. It can also be sent after every N trace points, so that the receiver
+recovers the time base quickly after lost data. To do so, set `UTRACE_RESYNC_INTERVAL` environment variable to N at
+build time.
+
 To define a transport, annotate a function with [#\[default_transport\]](crate::default_transport) like this:
 
 ```ignore
 #[utrace::default_transport]
-pub fn write(buf: &[u8]) {
+pub fn write(buf: &[u8]) -> usize {
     ...
 }
 ```
 
+The transport function returns the number of bytes it accepted. If only a part of a packet fits, utrace sends its rest
+before any other data. If a packet does not fit at all, utrace counts it as dropped and reports the number of dropped
+trace points to the host as soon as the transport accepts data again.
+
 The current implementation provides the implementation of RTT-based transport in *utrace_rtt* crate.
 
+By default, the transport is called from the traced code, inside a critical section, so a slow transport increases
+interrupt latency. To avoid this, enable `buffered` feature of utrace. Trace points are then written into a RAM ring
+buffer, and `utrace::flush()` sends them to the transport. Call it periodically, for example from idle loop or a
+low-priority task. The buffer size is set by `UTRACE_BUFFER_SIZE` environment variable at build time (1024 bytes by
+default, must be a power of two). If the buffer is full, trace points are dropped and reported as described above.
+
+If the trace can't be streamed continuously, and only the history leading up to a fault is of interest, enable
+`flight_recorder` feature instead. The buffer then keeps the latest trace points, overwriting the oldest ones, and
+nothing is sent until `utrace::dump()` is called, for example from a panic or HardFault handler. It sends the buffered
+trace along with the time base, so *utrace-capture* shows the trace points with correct timestamps. The transport should
+block until the data is accepted (for example, RTT channel in `BlockIfFull` mode).
+
+Panics can be recorded in the trace: call [panic_hook] from the panic handler, or link *utrace_panic* crate, which
+provides a panic handler similar to *panic-probe*, with `use utrace_panic as _;`. The location of the panic is recorded,
+the trace, which is still in RAM, is sent to the transport, and the firmware halts. *utrace-capture* marks the panic
+across the whole trace and closes the spans, which were open at that moment.
+
 Note, that current implementation requires an implementation of a critical section. For example, if you
 are using single-core ARM MCU, you can add
 
@@ -66,6 +148,28 @@ cortex-m = { version = "0.7.7", features = ["critical-section-single-core"] }
 
 to your Cargo.toml.
 
+Some transports drop data when the host does not keep up (for example, RTT channel in `NoBlockSkip` mode). To detect
+this, enable `sequence` feature of utrace. With it, a sequence packet is emitted after every 32 trace points, and the
+trace stream receiver reports how many trace points were lost in between. The interval can be changed at build time
+with `UTRACE_SEQUENCE_INTERVAL` environment variable.
+
+On multi-core MCUs (for example, RP2040), enable `multicore` feature of utrace and provide a function, which returns
+the id of the current core, with [#\[core_id\]](crate::core_id) macro. Each core then keeps its own timestamp state,
+trace points are tagged with the id of the core which emitted them, and *utrace-capture* shows every core as a separate
+track. The maximum number of cores is set by `UTRACE_MAX_CORES` environment variable at build time (2 by default).
+Note, that the critical section implementation must then be multi-core safe.
+
+Tracing can be switched off and on at run time with [set_enabled]. Single trace points, for example frequently hit
+ones, can be disabled with [set_trace_point_enabled], using their ids from the elf file. This is done with a bitmap in
+RAM, which covers trace points with ids below `UTRACE_ENABLE_BITMAP_SIZE` environment variable, set at build time (256
+by default); trace points with larger ids are always enabled, and [set_trace_point_enabled] returns false for them.
+*utrace-capture* warns when asked to disable them, assuming the default size.
+
+With `commands` feature of utrace, tracing can be controlled by the host while the firmware runs, see below. Pass the
+data, received from the host, to `utrace::process_commands()`. *utrace_rtt* does this for an RTT down channel: enable
+its `commands` feature, pass the channel to `utrace_rtt::init_commands()` and call `utrace_rtt::poll_commands()`
+periodically. Skip limits of at most `UTRACE_SKIP_OVERRIDES` trace points (8 by default) can be changed at run time.
+
 ## Trace data interpretation
 The metadata, required for trace interpretation is stored in the output elf binary. Correct bundling of this metadata requires passing
 *utrace_linker.x* script to a linker during your binary linking. It could be done either in *build.rs* script by adding something like
@@ -83,6 +187,10 @@ rustflags = [
 ```
 to your *.cargo/config.toml*. The first method should be prefered to the second one.
 
+Besides the metadata, trace points put a small table into the loaded part of the binary. On [init], the
+firmware computes its build ID from this table and sends it to the host. The trace stream receiver computes the same
+value from the elf file and ignores the trace, if the elf file does not match the running firmware.
+
 To extract metadata and interpret trace data stream, *utrace_parser* crate should be used. This crate's package provides a binary, called *utrace-capture*, which can receive raw trace stream from TCP connection or stdin and write the trace in *chrome://tracing* format. To install it, execute
 
 ```bash
@@ -113,13 +221,51 @@ utrace-capture <path to firmware elf executable> --tcp-server 0.0.0.0:9001 --out
 ```
 
 Trace data can also be captured from stdin using `--stdin` flag.
+
+If the firmware with `flight_recorder` feature can't send the trace anymore, for example because it is stuck, the trace
+can be read from a memory dump, saved by a debugger. *utrace-capture* finds the buffer by its symbols in the elf file,
+either in an elf core file, or in a raw dump of RAM, given with its start address:
+
+```bash
+utrace-capture <path to firmware elf executable> --memory ram.bin --base 0x20000000 --out-ct trace_out
+```
+
+If the firmware is built with `commands` feature, *utrace-capture* can send commands to it over the TCP connection.
+Commands, given with `-x` flag, are sent as soon as the connection is established, and with `-i` flag, commands are read
+from stdin while capturing, one per line. For example, to disable trace points of `do_something` function, run
+
+```bash
+utrace-capture <path to firmware elf executable> --tcp localhost:9001 --out-ct trace_out -x "disable do_something"
+```
+
+Supported commands are `start` and `stop` (enable and disable tracing), `enable NAME` and `disable NAME`, `skip NAME N`
+(for trace points with `skip` attribute), `init` and `resync`. Trace points are found by their name or comment in the
+elf file.
  */
 
-use utrace_core::encoding::TracePoint;
-pub use utrace_macros::{default_transport, timestamp, trace, trace_here};
+// Paths in the code, generated by utrace_macros, start with `utrace`
+extern crate self as utrace;
+
+pub use utrace_core::encoding::Value;
+pub use utrace_macros::{
+    core_id, counter, default_transport, log, timestamp, trace, trace_here, trace_isr, trace_value,
+};
 
+#[allow(dead_code)]
+mod config {
+    include!(concat!(env!("OUT_DIR"), "/config.rs"));
+}
 mod globals;
 
+#[cfg(feature = "buffered")]
+mod buffer;
+
+#[cfg(feature = "commands")]
+mod command;
+
+#[cfg(test)]
+mod test_support;
+
 /// Internal RAII tracer implementation.
 ///
 /// Calls to this API are always generated by macros;
@@ -131,14 +277,106 @@ pub use tracer::Tracer;
 /// This function can be called during initialization.
 /// Its current purpose is to establish reference time,
 /// relative to which all events will be timestamped and
-/// to emit Reset packet. This packet can be used by
+/// to emit Reset packet, which carries the session header
+/// describing the encoding. This packet can be used by
 /// trace stream capture tool to start a new trace. It
 /// is possible to call `init` multiple times during execution
-/// to logically separate the trace into several parts.
+/// to logically separate the trace into several parts. Trace
+/// points, dropped by the transport before `init`, are not
+/// reported. Build ID of the firmware is sent as a part of
+/// the reset sequence. If the transport does not accept the
+/// reset sequence, it is sent again before the next trace
+/// point, and trace points are dropped until it is accepted.
 pub fn init() {
-    let _ = crate::globals::default_timestamp_delta();
-    utrace_core::encoding::encode(
-        TracePoint { delta_t: 0, id: 0 },
-        crate::globals::default_write,
+    critical_section::with(|_| Tracer::reset());
+}
+
+/// Sends the trace, buffered in RAM, to the transport. Available with
+/// `buffered` feature, in which trace points are only put into the buffer,
+/// and this function should be called periodically, for example from
+/// idle loop or a low-priority task. It stops when the transport is full,
+/// the rest of the data is sent by the next call.
+#[cfg(all(feature = "buffered", not(feature = "flight_recorder")))]
+pub fn flush() {
+    crate::buffer::flush();
+}
+
+/// Sends the trace, kept by the flight recorder, to the transport. Available
+/// with `flight_recorder` feature, in which only the latest trace points are
+/// kept in RAM, and nothing is sent otherwise. It can be called from a panic
+/// handler or a fault handler, and the transport should block until the data
+/// is accepted. Returns false if the transport did not accept all of it; the
+/// buffer is empty afterwards anyway.
+#[cfg(feature = "flight_recorder")]
+pub fn dump() -> bool {
+    crate::buffer::dump()
+}
+
+/// Records a panic with its location and sends the trace, which is still in
+/// RAM, to the transport. Call it from a panic handler before the firmware
+/// halts, or use *utrace_panic* crate, which provides such a handler. The
+/// panic is recorded even if tracing is disabled. Without `flight_recorder`
+/// feature, it retries until the transport accepts the whole trace, so it
+/// does not return if the host does not read it. The file name is not sent,
+/// only its address in the firmware, and *utrace-capture* reads it from the
+/// elf file.
+pub fn panic_hook(info: &core::panic::PanicInfo) {
+    let (file, line, column) = match info.location() {
+        Some(location) => (location.file(), location.line(), location.column()),
+        None => ("", 0, 0),
+    };
+
+    Tracer::panic(
+        utrace_macros::panic_trace_point!(),
+        &[
+            Value::U32(file.as_ptr() as u32),
+            Value::U32(file.len() as u32),
+            Value::U32(line),
+            Value::U32(column),
+        ],
     );
+
+    // The panic might have interrupted a flush or left a part of a packet unsent
+    #[cfg(not(feature = "buffered"))]
+    critical_section::with(|_| crate::globals::drain());
+    #[cfg(all(feature = "buffered", not(feature = "flight_recorder")))]
+    critical_section::with(|_| crate::buffer::drain());
+    #[cfg(feature = "flight_recorder")]
+    crate::buffer::dump();
+}
+
+/// Emits a marker with an arbitrary code, for example to mark a phase of
+/// a test. It is shown across the whole trace.
+pub fn marker(code: u32) {
+    Tracer::emit_event(tracer::Event::Marker(code));
+}
+
+/// Reports that the current core switched to another task. It is intended
+/// for RTOS integration and should be called from the context switch hook
+/// with an identifier of the task, which starts running.
+pub fn task_switch(task: u32) {
+    Tracer::emit_event(tracer::Event::TaskSwitch(task));
+}
+
+/// Enables or disables tracing globally. Tracing is enabled by default.
+/// Spans, which were entered before tracing is disabled, are still closed.
+pub fn set_enabled(enabled: bool) {
+    crate::globals::set_enabled(enabled);
+}
+
+/// Enables or disables a single trace point, identified by its id, which
+/// is stored in the elf file. All trace points are enabled by default.
+/// Only ids below `UTRACE_ENABLE_BITMAP_SIZE` can be disabled, other trace
+/// points are always enabled, and false is returned for them.
+pub fn set_trace_point_enabled(id: u16, enabled: bool) -> bool {
+    crate::globals::set_point_enabled(id, enabled)
+}
+
+/// Applies commands, sent by the host, for example by *utrace-capture*, to
+/// enable or disable trace points, change their skip limits, or re-initialize
+/// tracing. Available with `commands` feature. Pass the data, received from
+/// the host, as it arrives; a command might be split between calls.
+#[cfg(feature = "commands")]
+pub fn process_commands(data: &[u8]) {
+    crate::command::process(data);
 }
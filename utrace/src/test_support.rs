@@ -0,0 +1,82 @@
+// Transport and timestamp function for the tests, which run on the host.
+// Not every test is built with every feature set.
+#![allow(dead_code)]
+
+extern crate std;
+
+use std::sync::{Mutex, MutexGuard};
+use std::vec::Vec;
+
+use utrace_core::encoding::{Decoder, Packet};
+
+static STREAM: Mutex<Vec<u8>> = Mutex::new(Vec::new());
+// Maximum number of bytes, accepted by a single transport call
+static ACCEPT: Mutex<usize> = Mutex::new(usize::MAX);
+static NOW: Mutex<u64> = Mutex::new(0);
+static CORE: Mutex<u8> = Mutex::new(0);
+// Tests share the statics of the crate, so they take turns
+static LOCK: Mutex<()> = Mutex::new(());
+
+#[crate::default_transport]
+fn transport(buf: &[u8]) -> usize {
+    let len = buf.len().min(*ACCEPT.lock().unwrap());
+    STREAM.lock().unwrap().extend_from_slice(&buf[..len]);
+    len
+}
+
+// Not the default time base, so that conversions of intervals are visible
+#[crate::timestamp(freq_hz = 2_000_000)]
+fn timestamp() -> u64 {
+    *NOW.lock().unwrap()
+}
+
+#[cfg(feature = "multicore")]
+#[crate::core_id]
+fn core_id() -> u8 {
+    *CORE.lock().unwrap()
+}
+
+// Empty build ID table, which is defined by utrace_linker.x on the target
+core::arch::global_asm!(
+    ".pushsection .rodata",
+    ".balign 8",
+    ".globl __utrace_build_id_start",
+    ".globl __utrace_build_id_end",
+    "__utrace_build_id_start:",
+    "__utrace_build_id_end:",
+    ".popsection",
+);
+
+/// Clears the captured stream and makes the transport accept everything
+pub(crate) fn lock() -> MutexGuard<'static, ()> {
+    let guard = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    STREAM.lock().unwrap().clear();
+    set_accept(usize::MAX);
+    guard
+}
+
+pub(crate) fn set_accept(len: usize) {
+    *ACCEPT.lock().unwrap() = len;
+}
+
+/// Sets the id, returned by the core id function
+pub(crate) fn set_core(core: u8) {
+    *CORE.lock().unwrap() = core;
+}
+
+/// Sets the time, returned by the timestamp function
+pub(crate) fn set_time(now: u64) {
+    *NOW.lock().unwrap() = now;
+}
+
+pub(crate) fn take_stream() -> Vec<u8> {
+    core::mem::take(&mut *STREAM.lock().unwrap())
+}
+
+pub(crate) fn decode(stream: &[u8]) -> Vec<Packet> {
+    let mut decoder = Decoder::new();
+    stream
+        .iter()
+        .filter_map(|b| decoder.push_byte(*b))
+        .collect()
+}
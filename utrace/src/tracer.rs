@@ -1,57 +1,423 @@
+use core::cell::Cell;
+use core::sync::atomic::AtomicU32;
+
+use critical_section::Mutex;
+
 use utrace_core::encoding::encode;
+use utrace_core::encoding::encode_build_id;
+use utrace_core::encoding::encode_instance;
+use utrace_core::encoding::encode_marker;
+use utrace_core::encoding::encode_overflow;
+use utrace_core::encoding::encode_reset;
+use utrace_core::encoding::encode_task_switch;
+use utrace_core::encoding::encode_timestamp;
+use utrace_core::encoding::encode_value;
 use utrace_core::encoding::TracePoint;
+use utrace_core::encoding::Value;
+
+use crate::globals::TimestampDelta;
+
+// Number of trace points, which were not accepted by the transport and
+// are not reported to the host yet. Accessed from critical sections only.
+static mut DROPPED_POINTS: u32 = 0;
+
+// The reset sequence was not accepted by the transport and is sent again
+// before the next event. Accessed from critical sections only.
+static mut RESET_PENDING: bool = false;
+
+/// Build ID table entry, emitted by trace point macros for each trace point
+#[repr(C)]
+pub struct BuildIdEntry {
+    /// Hash of the trace point metadata
+    pub hash: u32,
+    /// Trace point, its address is the trace point id
+    pub id: &'static u8,
+}
+
+/// Timestamped event, written to the trace stream
+#[derive(Clone, Copy)]
+pub(crate) enum Event {
+    Point(u16),
+    /// Trace point of an instance of a traced async fn
+    InstancePoint(u16, u32),
+    Marker(u32),
+    TaskSwitch(u32),
+}
 
 pub struct Tracer {
-    exit_id: Option<u8>,
+    exit_id: Option<u16>,
+    instance: Option<u32>,
+    // Calls suppressed by min_interval_us, reported with the exit if there is no entry
+    suppressed: u32,
 }
 
-pub enum SkipConfig {
+/// Sampling state is usually static, but polls of each future are counted separately
+pub enum SkipConfig<'a> {
     NoSkip,
     Skip {
-        counter: &'static mut u32,
+        counter: &'a AtomicU32,
         limit: u32,
     },
+    MinInterval {
+        state: &'a IntervalState,
+        interval_us: u32,
+    },
 }
 
-impl Tracer {
-    pub fn new(entry_id: Option<u8>, exit_id: Option<u8>, skip_config: SkipConfig) -> Option<Self> {
-        match skip_config {
-            SkipConfig::NoSkip => {
-                if let Some(id) = entry_id {
-                    Self::emit(id);
+/// Sampling state of a trace point with `min_interval_us`
+pub struct IntervalState {
+    // Timestamp of the last reported call and the number of calls suppressed since then
+    last: Mutex<Cell<Option<(u64, u32)>>>,
+}
+
+impl IntervalState {
+    pub const fn new() -> Self {
+        IntervalState {
+            last: Mutex::new(Cell::new(None)),
+        }
+    }
+
+    // Returns the number of suppressed calls if this one should be reported
+    fn sample(&self, interval_us: u32) -> Option<u32> {
+        let interval = interval_us as u64 * crate::globals::timestamp_freq_hz() / 1_000_000;
+        let now = crate::globals::timestamp();
+
+        critical_section::with(|cs| {
+            let last = self.last.borrow(cs);
+            match last.get() {
+                Some((timestamp, suppressed)) if now.wrapping_sub(timestamp) < interval => {
+                    last.set(Some((timestamp, suppressed.saturating_add(1))));
+                    None
+                }
+                Some((_, suppressed)) => {
+                    last.set(Some((now, 0)));
+                    Some(suppressed)
+                }
+                None => {
+                    last.set(Some((now, 0)));
+                    Some(0)
                 }
-                Some(Tracer { exit_id })
             }
+        })
+    }
+}
+
+impl Default for IntervalState {
+    fn default() -> Self {
+        IntervalState::new()
+    }
+}
+
+impl Tracer {
+    /// Instance is given for the polls of async fns, so that the host
+    /// can tell concurrent instances of the same fn apart
+    pub fn new(
+        entry_id: Option<u16>,
+        exit_id: Option<u16>,
+        skip_config: SkipConfig<'_>,
+        instance: Option<u32>,
+    ) -> Option<Self> {
+        if !Self::is_enabled(entry_id, exit_id) {
+            return None;
+        }
+
+        let suppressed = Self::sample(entry_id.or(exit_id), skip_config)?;
+        Some(Self::start(entry_id, exit_id, instance, suppressed))
+    }
+
+    /// Starts tracing of a new instance of an async fn. The instance is sampled
+    /// once, at its creation, so that either its creation, polls and drop are
+    /// reported, or none of them. An instance, created while its trace points are
+    /// disabled, is not sampled. Returns the id of the instance, if it is sampled.
+    pub fn new_instance(
+        entry_id: Option<u16>,
+        exit_id: Option<u16>,
+        skip_config: SkipConfig<'_>,
+    ) -> (Option<Self>, Option<u32>) {
+        if !Self::is_enabled(entry_id, exit_id) {
+            return (None, None);
+        }
+
+        let Some(suppressed) = Self::sample(entry_id.or(exit_id), skip_config) else {
+            return (None, None);
+        };
+
+        let instance = crate::globals::next_instance();
+        let tracer = Self::start(entry_id, exit_id, Some(instance), suppressed);
+
+        (Some(tracer), Some(instance))
+    }
+
+    /// Allocates an id for a new instance of an async fn, which only traces its polls
+    pub fn next_instance() -> u32 {
+        crate::globals::next_instance()
+    }
+
+    // Exit is still emitted if the trace point is disabled in between,
+    // so that spans stay balanced
+    fn is_enabled(entry_id: Option<u16>, exit_id: Option<u16>) -> bool {
+        match entry_id.or(exit_id) {
+            Some(id) => crate::globals::is_point_enabled(id),
+            None => true,
+        }
+    }
+
+    // Returns the number of suppressed calls, if this call should be reported
+    fn sample(id: Option<u16>, skip_config: SkipConfig<'_>) -> Option<u32> {
+        match skip_config {
+            SkipConfig::NoSkip => Some(0),
             SkipConfig::Skip { counter, limit } => {
-                *counter += 1;
-                if *counter >= limit {
-                    if let Some(id) = entry_id {
-                        Self::emit(id);
-                    }
-                    *counter = 0;
-                    Some(Tracer { exit_id })
-                } else {
-                    None
+                // The host might have changed the limit
+                #[cfg(feature = "commands")]
+                let limit = id.and_then(crate::command::skip_limit).unwrap_or(limit);
+                #[cfg(not(feature = "commands"))]
+                let _ = id;
+
+                // The counter is reset when a call is reported
+                let next = |count: u32| if count + 1 >= limit { 0 } else { count + 1 };
+                let count = crate::globals::update(counter, next);
+
+                (next(count) == 0).then_some(0)
+            }
+            SkipConfig::MinInterval { state, interval_us } => state.sample(interval_us),
+        }
+    }
+
+    fn start(
+        entry_id: Option<u16>,
+        exit_id: Option<u16>,
+        instance: Option<u32>,
+        suppressed: u32,
+    ) -> Self {
+        match entry_id {
+            Some(id) => {
+                Self::emit(id, instance, suppressed);
+                Tracer {
+                    exit_id,
+                    instance,
+                    suppressed: 0,
                 }
             }
+            None => Tracer {
+                exit_id,
+                instance,
+                suppressed,
+            },
         }
     }
 
-    fn emit(id: u8) {
+    /// Starts tracing of an interrupt handler. The active priority level,
+    /// if given, is sent with the entry point
+    pub fn isr(entry_id: u16, exit_id: u16, priority: Option<Value>) -> Option<Self> {
+        if !crate::globals::is_point_enabled(entry_id) {
+            return None;
+        }
+
         critical_section::with(|_| {
-            let delta = crate::globals::default_timestamp_delta();
-            encode(
-                TracePoint { delta_t: delta, id },
-                crate::globals::default_write,
-            );
+            Self::write_event(Event::Point(entry_id), priority.as_slice());
+        });
+
+        Some(Tracer {
+            exit_id: Some(exit_id),
+            instance: None,
+            suppressed: 0,
+        })
+    }
+
+    /// Emits a single trace point, which carries a value.
+    pub fn value(id: u16, value: Value) {
+        if !crate::globals::is_point_enabled(id) {
+            return;
+        }
+
+        critical_section::with(|_| {
+            Self::write_event(Event::Point(id), &[value]);
+        });
+    }
+
+    /// Emits a single trace point, which carries log message arguments.
+    pub fn log(id: u16, args: &[Value]) {
+        if !crate::globals::is_point_enabled(id) {
+            return;
+        }
+
+        critical_section::with(|_| {
+            Self::write_event(Event::Point(id), args);
+        });
+    }
+
+    // Panic is reported even if tracing is disabled
+    pub(crate) fn panic(id: u16, location: &[Value]) {
+        critical_section::with(|_| {
+            Self::write_event(Event::Point(id), location);
+        });
+    }
+
+    fn emit(id: u16, instance: Option<u32>, suppressed: u32) {
+        let event = match instance {
+            Some(instance) => Event::InstancePoint(id, instance),
+            None => Event::Point(id),
+        };
+        let values: &[Value] = if suppressed > 0 {
+            &[Value::U32(suppressed)]
+        } else {
+            &[]
+        };
+
+        critical_section::with(|_| {
+            Self::write_event(event, values);
+        });
+    }
+
+    pub(crate) fn emit_event(event: Event) {
+        if !crate::globals::is_enabled() {
+            return;
+        }
+
+        critical_section::with(|_| {
+            Self::write_event(event, &[]);
         });
     }
+
+    /// Establishes reference time and sends the reset sequence, which starts a
+    /// new trace. Must be called from a critical section
+    pub(crate) fn reset() {
+        unsafe { RESET_PENDING = !Self::send_reset() };
+    }
+
+    // Returns false if the transport did not accept the whole reset sequence.
+    // Must be called from a critical section
+    fn send_reset() -> bool {
+        let timestamp = crate::globals::default_timestamp_reset();
+        let sent = encode_reset(
+            crate::globals::session_header(),
+            crate::globals::default_write_all,
+        ) && encode_build_id(
+            crate::globals::build_id(),
+            crate::globals::default_write_all,
+        );
+        #[cfg(not(feature = "multicore"))]
+        let sent = sent && encode_timestamp(timestamp, crate::globals::default_write_all);
+        #[cfg(feature = "multicore")]
+        let sent = sent && crate::globals::default_core_reset(timestamp);
+
+        // Trace points, dropped before the reset, are not reported
+        unsafe { DROPPED_POINTS = 0 };
+        #[cfg(feature = "sequence")]
+        crate::globals::default_sequence_reset();
+
+        sent
+    }
+
+    // Values and the instance are sent before the event they belong to.
+    // Must be called from a critical section
+    fn write_event(event: Event, values: &[Value]) {
+        // The receiver can't interpret events without the session header,
+        // so they are not even counted as dropped
+        if unsafe { RESET_PENDING } {
+            if !Self::send_reset() {
+                return;
+            }
+            unsafe { RESET_PENDING = false };
+        }
+
+        // There is no timestamp state for a core, whose id is out of range
+        let Some(core) = crate::globals::current_core() else {
+            Self::drop_point();
+            return;
+        };
+
+        if !Self::report_dropped() {
+            Self::drop_point();
+            return;
+        }
+
+        #[cfg(feature = "multicore")]
+        if !crate::globals::default_core_switch(core) {
+            Self::drop_point();
+            return;
+        }
+
+        for value in values {
+            if !encode_value(*value, crate::globals::default_write_all) {
+                Self::drop_point();
+                return;
+            }
+        }
+
+        if let Event::InstancePoint(_, instance) = event {
+            if !encode_instance(instance, crate::globals::default_write_all) {
+                Self::drop_point();
+                return;
+            }
+        }
+
+        let (delta, resynced) = match crate::globals::default_timestamp_delta(core) {
+            TimestampDelta::Relative(delta) => (delta, false),
+            TimestampDelta::Absolute(timestamp) => {
+                if !encode_timestamp(timestamp, crate::globals::default_write_all) {
+                    crate::globals::default_timestamp_rollback(core);
+                    Self::drop_point();
+                    return;
+                }
+                (0, true)
+            }
+        };
+
+        let sent = match event {
+            Event::Point(id) | Event::InstancePoint(id, _) => encode(
+                TracePoint { delta_t: delta, id },
+                crate::globals::default_write_all,
+            ),
+            Event::Marker(code) => encode_marker(code, delta, crate::globals::default_write_all),
+            Event::TaskSwitch(task) => {
+                encode_task_switch(task, delta, crate::globals::default_write_all)
+            }
+        };
+
+        if sent {
+            // Only trace points are counted by sequence packets
+            #[cfg(feature = "sequence")]
+            if let Event::Point(_) | Event::InstancePoint(..) = event {
+                crate::globals::default_sequence_tick();
+            }
+        } else {
+            // The receiver is already anchored to the new timestamp if it was sent
+            if !resynced {
+                crate::globals::default_timestamp_rollback(core);
+            }
+            Self::drop_point();
+        }
+    }
+
+    // Must be called from a critical section
+    fn drop_point() {
+        unsafe { DROPPED_POINTS = DROPPED_POINTS.saturating_add(1) };
+    }
+
+    // Sends an overflow packet if there are any dropped trace points.
+    // Returns false if the transport is still full.
+    // Must be called from a critical section
+    fn report_dropped() -> bool {
+        let dropped = unsafe { DROPPED_POINTS };
+
+        if dropped == 0 {
+            return true;
+        }
+
+        if encode_overflow(dropped, crate::globals::default_write_all) {
+            unsafe { DROPPED_POINTS = 0 };
+            true
+        } else {
+            false
+        }
+    }
 }
 
 impl Drop for Tracer {
     fn drop(&mut self) {
         if let Some(id) = self.exit_id {
-            Self::emit(id);
+            Self::emit(id, self.instance, self.suppressed);
         }
     }
 }
@@ -61,11 +427,13 @@ impl core::fmt::Debug for Tracer {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         f.debug_struct("Tracer")
             .field("exit_id", &self.exit_id)
+            .field("instance", &self.instance)
+            .field("suppressed", &self.suppressed)
             .finish()
     }
 }
 
-impl core::fmt::Debug for SkipConfig {
+impl core::fmt::Debug for SkipConfig<'_> {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         match self {
             SkipConfig::NoSkip => write!(f, "NoSkip"),
@@ -74,6 +442,206 @@ impl core::fmt::Debug for SkipConfig {
                 .field("counter", counter)
                 .field("limit", limit)
                 .finish(),
+            SkipConfig::MinInterval { interval_us, .. } => f
+                .debug_struct("MinInterval")
+                .field("interval_us", interval_us)
+                .finish(),
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+
+    use core::sync::atomic::AtomicU32;
+    use std::vec::Vec;
+
+    use super::*;
+    use crate::test_support;
+
+    #[test]
+    fn instance_sampled_once() {
+        let _lock = test_support::lock();
+        static COUNTER: AtomicU32 = AtomicU32::new(0);
+        let skip = || SkipConfig::Skip {
+            counter: &COUNTER,
+            limit: 2,
+        };
+
+        let (tracer, instance) = Tracer::new_instance(Some(5), Some(5), skip());
+        assert!(tracer.is_none() && instance.is_none());
+
+        let (tracer, instance) = Tracer::new_instance(Some(5), Some(5), skip());
+        assert!(tracer.is_some() && instance.is_some());
+
+        // Instance, created while tracing is off, stays unsampled
+        crate::globals::set_point_enabled(5, false);
+        let (tracer, instance) = Tracer::new_instance(Some(5), Some(5), skip());
+        crate::globals::set_point_enabled(5, true);
+        assert!(tracer.is_none() && instance.is_none());
+    }
+
+    #[cfg(not(feature = "buffered"))]
+    #[test]
+    fn reset_retried() {
+        use utrace_core::encoding::Packet;
+
+        let _lock = test_support::lock();
+
+        test_support::set_accept(0);
+        crate::init();
+        crate::marker(1);
+        test_support::set_accept(usize::MAX);
+        crate::marker(2);
+
+        // The marker, emitted before the session header, is not reported as lost
+        let packets: Vec<_> = test_support::decode(&test_support::take_stream())
+            .into_iter()
+            .filter(|p| {
+                matches!(
+                    p,
+                    Packet::Reset(_)
+                        | Packet::BuildId(_)
+                        | Packet::Overflow(_)
+                        | Packet::Marker { .. }
+                )
+            })
+            .collect();
+        assert!(matches!(
+            packets[..],
+            [
+                Packet::Reset(_),
+                Packet::BuildId(_),
+                Packet::Marker { code: 2, .. }
+            ]
+        ));
+    }
+
+    #[cfg(all(feature = "multicore", not(feature = "buffered")))]
+    #[test]
+    fn core_out_of_range() {
+        use utrace_core::encoding::Packet;
+
+        let _lock = test_support::lock();
+        test_support::set_time(100);
+        crate::init();
+        test_support::take_stream();
+
+        test_support::set_time(110);
+        test_support::set_core(crate::config::MAX_CORES as u8);
+        crate::marker(1);
+        test_support::set_core(0);
+        test_support::set_time(120);
+        crate::marker(2);
+
+        // The event is lost, and the state of the core 0 is not affected
+        let packets: Vec<_> = test_support::decode(&test_support::take_stream())
+            .into_iter()
+            .filter(|p| matches!(p, Packet::Overflow(_) | Packet::Marker { .. }))
+            .collect();
+        assert!(matches!(
+            packets[..],
+            [
+                Packet::Overflow(1),
+                Packet::Marker {
+                    code: 2,
+                    delta_t: 20
+                }
+            ]
+        ));
+    }
+
+    // Sampling decisions for the calls of a trace point with `skip = 3`
+    fn skip_three(id: u16, counter: &AtomicU32, calls: usize) -> Vec<bool> {
+        (0..calls)
+            .map(|_| Tracer::sample(Some(id), SkipConfig::Skip { counter, limit: 3 }).is_some())
+            .collect()
+    }
+
+    #[test]
+    fn skip_counter() {
+        let _lock = test_support::lock();
+        let counter = AtomicU32::new(0);
+
+        assert_eq!(
+            skip_three(20, &counter, 6),
+            [false, false, true, false, false, true]
+        );
+    }
+
+    #[cfg(feature = "commands")]
+    #[test]
+    fn skip_override() {
+        use utrace_core::command::{encode_command, Command};
+
+        let _lock = test_support::lock();
+        let counter = AtomicU32::new(0);
+        let set_limit =
+            |limit| encode_command(Command::SetSkip { id: 20, limit }, crate::process_commands);
+
+        // The limit, set by the host, replaces the one of the attribute
+        set_limit(2);
+        assert_eq!(skip_three(20, &counter, 4), [false, true, false, true]);
+        assert_eq!(skip_three(21, &counter, 3), [false, false, true]);
+
+        set_limit(0);
+        assert_eq!(skip_three(20, &counter, 3), [false, false, true]);
+    }
+
+    #[test]
+    fn min_interval() {
+        let _lock = test_support::lock();
+        let state = IntervalState::new();
+        let mut sample = |now| {
+            test_support::set_time(now);
+            state.sample(100)
+        };
+
+        // The test timestamp counts at 2 MHz, so 100 us are 200 ticks
+        let reported: Vec<_> = [1000, 1100, 1199, 1200, 1300, 1400, 1600]
+            .into_iter()
+            .map(&mut sample)
+            .collect();
+
+        assert_eq!(
+            reported,
+            [Some(0), None, None, Some(2), None, Some(1), Some(0)]
+        );
+    }
+
+    #[cfg(not(feature = "buffered"))]
+    #[test]
+    fn min_interval_span() {
+        use utrace_core::encoding::Packet;
+
+        let _lock = test_support::lock();
+        let state = IntervalState::new();
+        let config = || SkipConfig::MinInterval {
+            state: &state,
+            interval_us: 100,
+        };
+
+        for now in [1000, 1100, 1200] {
+            test_support::set_time(now);
+            drop(Tracer::new(Some(5), Some(6), config(), None));
+        }
+
+        // Suppressed calls are reported with the entry of the next reported one
+        let packets: Vec<_> = test_support::decode(&test_support::take_stream())
+            .into_iter()
+            .filter(|p| matches!(p, Packet::TracePoint(_) | Packet::Value(_)))
+            .collect();
+
+        assert!(matches!(
+            packets[..],
+            [
+                Packet::TracePoint(TracePoint { id: 5, .. }),
+                Packet::TracePoint(TracePoint { id: 6, .. }),
+                Packet::Value(Value::U32(1)),
+                Packet::TracePoint(TracePoint { id: 5, .. }),
+                Packet::TracePoint(TracePoint { id: 6, .. }),
+            ]
+        ));
+    }
+}
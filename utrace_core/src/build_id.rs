@@ -0,0 +1,34 @@
+// Build ID binds a trace stream to the elf file it was produced by.
+//
+// Each trace point contributes a hash of its metadata and id, and the build ID is
+// a wrapping sum of these contributions, so it does not depend on the link order.
+// Target computes it from the table, placed in the elf by trace point macros, and
+// the trace stream receiver - from the trace point symbols.
+
+const FNV_OFFSET_BASIS: u32 = 0x811c_9dc5;
+const FNV_PRIME: u32 = 0x0100_0193;
+
+const fn fnv1a(mut hash: u32, data: &[u8]) -> u32 {
+    let mut i = 0;
+    while i < data.len() {
+        hash ^= data[i] as u32;
+        hash = hash.wrapping_mul(FNV_PRIME);
+        i += 1;
+    }
+    hash
+}
+
+/// Hash of trace point metadata, i.e. of its symbol name
+pub const fn metadata_hash(metadata: &[u8]) -> u32 {
+    fnv1a(FNV_OFFSET_BASIS, metadata)
+}
+
+/// Contribution of a single trace point to the build ID
+pub const fn trace_point_hash(metadata_hash: u32, id: u16) -> u32 {
+    fnv1a(metadata_hash, &id.to_le_bytes())
+}
+
+/// Adds a trace point contribution to the build ID
+pub const fn combine(build_id: u32, trace_point_hash: u32) -> u32 {
+    build_id.wrapping_add(trace_point_hash)
+}
@@ -0,0 +1,179 @@
+// Host-to-target command encoding
+//
+// Commands are framed like trace packets: the command kind, followed by at least one
+// 7-bit group of the argument, least significant first. The last byte of a command
+// has its high bit set. Kinds:
+// - 0: enable or disable tracing, argument is 1 or 0
+// - 1: enable or disable a trace point, id in the low 16 bits, 1 or 0 in bit 16
+// - 2: skip limit of a trace point, id in the low 16 bits, limit in the high 32 bits;
+//   0 restores the limit, set at compile time
+// - 3: re-initialize tracing, as if utrace::init was called
+// - 4: send absolute timestamp with the next trace point
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Command {
+    SetEnabled(bool),
+    SetTracePointEnabled { id: u16, enabled: bool },
+    SetSkip { id: u16, limit: u32 },
+    Init,
+    Resync,
+}
+
+const KIND_SET_ENABLED: u8 = 0;
+const KIND_SET_TRACE_POINT_ENABLED: u8 = 1;
+const KIND_SET_SKIP: u8 = 2;
+const KIND_INIT: u8 = 3;
+const KIND_RESYNC: u8 = 4;
+
+/// Maximum size of an encoded command
+pub const MAX_COMMAND_SIZE: usize = 1 + u64::BITS as usize / 7 + 1;
+
+impl Command {
+    fn to_raw(self) -> (u8, u64) {
+        match self {
+            Command::SetEnabled(enabled) => (KIND_SET_ENABLED, enabled as u64),
+            Command::SetTracePointEnabled { id, enabled } => (
+                KIND_SET_TRACE_POINT_ENABLED,
+                id as u64 | (enabled as u64) << 16,
+            ),
+            Command::SetSkip { id, limit } => (KIND_SET_SKIP, id as u64 | (limit as u64) << 16),
+            Command::Init => (KIND_INIT, 0),
+            Command::Resync => (KIND_RESYNC, 0),
+        }
+    }
+
+    fn from_raw(kind: u8, raw: u64) -> Option<Self> {
+        match kind {
+            KIND_SET_ENABLED if raw <= 1 => Some(Command::SetEnabled(raw == 1)),
+            KIND_SET_TRACE_POINT_ENABLED if raw >> 16 <= 1 => Some(Command::SetTracePointEnabled {
+                id: raw as u16,
+                enabled: raw >> 16 == 1,
+            }),
+            KIND_SET_SKIP if raw >> 48 == 0 => Some(Command::SetSkip {
+                id: raw as u16,
+                limit: (raw >> 16) as u32,
+            }),
+            KIND_INIT => Some(Command::Init),
+            KIND_RESYNC => Some(Command::Resync),
+            _ => None,
+        }
+    }
+}
+
+pub fn encode_command<W, R>(command: Command, writer: W) -> R
+where
+    W: for<'a> FnOnce(&'a [u8]) -> R,
+{
+    let mut outbuf = [0; MAX_COMMAND_SIZE];
+
+    let (kind, mut raw) = command.to_raw();
+    outbuf[0] = kind;
+
+    let mut len = 1;
+    loop {
+        outbuf[len] = (raw & 0x7f) as u8;
+        raw >>= 7;
+        len += 1;
+        if raw == 0 {
+            break;
+        }
+    }
+    outbuf[len - 1] |= 0x80;
+
+    writer(&outbuf[..len])
+}
+
+/// Splits a byte stream, received by the target, into commands. Malformed
+/// and overlong commands are skipped.
+pub struct CommandDecoder {
+    buf: [u8; MAX_COMMAND_SIZE],
+    // Length of the current command, might exceed the buffer size
+    len: usize,
+}
+
+impl CommandDecoder {
+    pub const fn new() -> Self {
+        CommandDecoder {
+            buf: [0; MAX_COMMAND_SIZE],
+            len: 0,
+        }
+    }
+
+    pub fn push_byte(&mut self, byte: u8) -> Option<Command> {
+        if self.len < MAX_COMMAND_SIZE {
+            self.buf[self.len] = byte;
+        }
+        self.len = self.len.saturating_add(1);
+
+        if byte & 0x80 == 0 {
+            return None;
+        }
+
+        let len = core::mem::replace(&mut self.len, 0);
+        match &self.buf[..len.min(MAX_COMMAND_SIZE)] {
+            [kind, groups @ ..] if len <= MAX_COMMAND_SIZE && !groups.is_empty() => {
+                let raw = groups
+                    .iter()
+                    .rev()
+                    .fold(0u64, |raw, b| raw << 7 | (b & 0x7f) as u64);
+                Command::from_raw(*kind, raw)
+            }
+            _ => None,
+        }
+    }
+}
+
+impl Default for CommandDecoder {
+    fn default() -> Self {
+        CommandDecoder::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use proptest::prelude::*;
+
+    use super::*;
+
+    fn arb_command() -> impl Strategy<Value = Command> {
+        prop_oneof![
+            any::<bool>().prop_map(Command::SetEnabled),
+            (any::<u16>(), any::<bool>())
+                .prop_map(|(id, enabled)| Command::SetTracePointEnabled { id, enabled }),
+            (any::<u16>(), any::<u32>()).prop_map(|(id, limit)| Command::SetSkip { id, limit }),
+            Just(Command::Init),
+            Just(Command::Resync),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn command_stream(commands in prop::collection::vec(arb_command(), 1..32)) {
+            let mut stream = vec![];
+            for command in &commands {
+                encode_command(*command, |buf| stream.extend_from_slice(buf));
+            }
+
+            let mut decoder = CommandDecoder::new();
+            let decoded: Vec<_> = stream.iter().filter_map(|b| decoder.push_byte(*b)).collect();
+
+            prop_assert_eq!(decoded, commands);
+        }
+    }
+
+    #[test]
+    fn test_garbage_resync() {
+        let mut stream = vec![0x80, 0x7f, 0x7f, 0xff];
+        stream.extend([0u8; 2 * MAX_COMMAND_SIZE]);
+        stream.push(0x80);
+        encode_command(Command::Init, |buf| stream.extend_from_slice(buf));
+
+        let mut decoder = CommandDecoder::new();
+        let decoded: Vec<_> = stream
+            .iter()
+            .filter_map(|b| decoder.push_byte(*b))
+            .collect();
+
+        assert_eq!(decoded, vec![Command::Init]);
+    }
+}
@@ -1,21 +1,272 @@
 // Simplistic comma-free self-synchronizing encoder/decoder
+//
+// Packet layout:
+// - trace point id, LEB128-encoded (ids below 128 take a single byte)
+// - 7-bit groups of delta_t, least significant first, at least two of them
+// - the last byte of a packet has its high bit set
+//
+// The last id byte and every delta group except the final one have the high bit
+// cleared, so the only place where a byte without the high bit is followed by a
+// byte with it is the end of a packet.
+//
+// Id 0 is never assigned to a trace point and marks a control packet instead.
+// The byte after it is the control packet kind, followed by kind-specific 7-bit
+// groups:
+// - 0: reset, session header (see SessionHeader), which determines the encoding
+//   of the following packets
+// - 1..=5: value, attached to the trace point packet that follows it
+// - 6: sequence, number of trace points emitted so far, modulo SEQUENCE_MODULO
+// - 7: overflow, number of trace points dropped because transport was full
+// - 8: timestamp, absolute value, following trace point deltas are relative to it
+// - 9: build ID of the firmware, see build_id module
+// - 10: core, following packets are emitted by this core
+// - 11: marker, code in the low 32 bits, delta_t in the high 32 bits
+// - 12: task switch, task in the low 32 bits, delta_t in the high 32 bits
+// - 13: instance of a traced async fn, attached to the trace point packet that follows it
+//
+// Markers and task switches carry delta_t, like trace points do.
 
 #[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub struct TracePoint {
     pub delta_t: u32,
-    pub id: u8,
+    pub id: u16,
 }
 
-pub fn encode<W>(tp: TracePoint, writer: W)
+/// Value, carried by a trace point
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Value {
+    U8(u8),
+    U16(u16),
+    U32(u32),
+    I32(i32),
+    F32(f32),
+}
+
+/// Decoded packet
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Packet {
+    TracePoint(TracePoint),
+    Value(Value),
+    Sequence(u16),
+    Overflow(u32),
+    Timestamp(u64),
+    BuildId(u32),
+    Core(u8),
+    Marker { code: u32, delta_t: u32 },
+    TaskSwitch { task: u32, delta_t: u32 },
+    Instance(u32),
+    Reset(SessionHeader),
+}
+
+/// Version of the encoding, described above
+pub const PROTOCOL_VERSION: u8 = 1;
+
+/// Session carries sequence packets
+pub const SESSION_FLAG_SEQUENCE: u16 = 1 << 0;
+/// Session carries core packets
+pub const SESSION_FLAG_MULTICORE: u16 = 1 << 1;
+/// Trace points are buffered by the target before being sent
+pub const SESSION_FLAG_BUFFERED: u16 = 1 << 2;
+/// Session is a dump of the flight recorder, which starts in the middle of the
+/// trace, so the first sequence packet does not tell about lost trace points
+pub const SESSION_FLAG_FLIGHT_RECORDER: u16 = 1 << 3;
+
+/// Session header, carried by a reset packet. It describes the encoding of the
+/// packets, which follow it.
+///
+/// Version 0 stands for the original encoding, without session header, where the
+/// trace point id was a single raw byte and the reset was a trace point with id 0.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct SessionHeader {
+    pub version: u8,
+    /// Width of trace point timestamp deltas in bits
+    pub timestamp_bits: u8,
+    /// Capabilities of the target, SESSION_FLAG_* bitmask
+    pub flags: u16,
+}
+
+impl SessionHeader {
+    /// Header of the original encoding
+    pub const LEGACY: SessionHeader = SessionHeader {
+        version: 0,
+        timestamp_bits: 0,
+        flags: 0,
+    };
+
+    fn to_raw(self) -> u64 {
+        self.version as u64 | (self.timestamp_bits as u64) << 8 | (self.flags as u64) << 16
+    }
+
+    fn from_raw(raw: u64) -> Self {
+        // Version 0 headers are always zero, regardless of what follows
+        if raw & 0xff == 0 {
+            return SessionHeader::LEGACY;
+        }
+
+        SessionHeader {
+            version: raw as u8,
+            timestamp_bits: (raw >> 8) as u8,
+            flags: (raw >> 16) as u16,
+        }
+    }
+}
+
+/// Header of the current encoding without any capabilities
+impl Default for SessionHeader {
+    fn default() -> Self {
+        SessionHeader {
+            version: PROTOCOL_VERSION,
+            timestamp_bits: u32::BITS as u8,
+            flags: 0,
+        }
+    }
+}
+
+const CONTROL_ID: u8 = 0;
+
+const KIND_RESET: u8 = 0;
+const KIND_U8: u8 = 1;
+const KIND_U16: u8 = 2;
+const KIND_U32: u8 = 3;
+const KIND_I32: u8 = 4;
+const KIND_F32: u8 = 5;
+const KIND_SEQUENCE: u8 = 6;
+const KIND_OVERFLOW: u8 = 7;
+const KIND_TIMESTAMP: u8 = 8;
+const KIND_BUILD_ID: u8 = 9;
+const KIND_CORE: u8 = 10;
+const KIND_MARKER: u8 = 11;
+const KIND_TASK_SWITCH: u8 = 12;
+const KIND_INSTANCE: u8 = 13;
+
+/// Sequence numbers carried by [Packet::Sequence] wrap around at this value
+pub const SEQUENCE_MODULO: u32 = 1 << 14;
+
+const MAX_ID_SIZE: usize = u16::BITS as usize / 7 + 1;
+const MAX_TS_SIZE: usize = u32::BITS as usize / 7 + 1;
+
+const MAX_TRACE_POINT_SIZE: usize = MAX_ID_SIZE + MAX_TS_SIZE;
+const MAX_CONTROL_SIZE: usize = 2 + u64::BITS as usize / 7 + 1;
+
+/// Maximum length of a single encoded packet in bytes
+pub const MAX_PACKET_SIZE: usize = if MAX_TRACE_POINT_SIZE > MAX_CONTROL_SIZE {
+    MAX_TRACE_POINT_SIZE
+} else {
+    MAX_CONTROL_SIZE
+};
+
+impl Value {
+    fn to_raw(self) -> (u8, u32) {
+        match self {
+            Value::U8(v) => (KIND_U8, v as u32),
+            Value::U16(v) => (KIND_U16, v as u32),
+            Value::U32(v) => (KIND_U32, v),
+            Value::I32(v) => (KIND_I32, ((v << 1) ^ (v >> 31)) as u32),
+            Value::F32(v) => (KIND_F32, v.to_bits()),
+        }
+    }
+
+    fn from_raw(kind: u8, raw: u32) -> Option<Self> {
+        match kind {
+            KIND_U8 => Some(Value::U8(raw as u8)),
+            KIND_U16 => Some(Value::U16(raw as u16)),
+            KIND_U32 => Some(Value::U32(raw)),
+            KIND_I32 => Some(Value::I32(((raw >> 1) as i32) ^ -((raw & 1) as i32))),
+            KIND_F32 => Some(Value::F32(f32::from_bits(raw))),
+            _ => None,
+        }
+    }
+}
+
+impl From<u8> for Value {
+    fn from(v: u8) -> Self {
+        Value::U8(v)
+    }
+}
+
+impl From<u16> for Value {
+    fn from(v: u16) -> Self {
+        Value::U16(v)
+    }
+}
+
+impl From<u32> for Value {
+    fn from(v: u32) -> Self {
+        Value::U32(v)
+    }
+}
+
+impl From<i32> for Value {
+    fn from(v: i32) -> Self {
+        Value::I32(v)
+    }
+}
+
+impl From<f32> for Value {
+    fn from(v: f32) -> Self {
+        Value::F32(v)
+    }
+}
+
+#[cfg(feature = "std")]
+impl core::fmt::Display for Value {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Value::U8(v) => write!(f, "{}", v),
+            Value::U16(v) => write!(f, "{}", v),
+            Value::U32(v) => write!(f, "{}", v),
+            Value::I32(v) => write!(f, "{}", v),
+            Value::F32(v) => write!(f, "{}", v),
+        }
+    }
+}
+
+fn write_groups(buf: &mut [u8], mut v: u64) -> usize {
+    let mut len = 0;
+
+    loop {
+        buf[len] = (v & 0x7f) as u8;
+        v >>= 7;
+        len += 1;
+        if v == 0 {
+            break;
+        }
+    }
+
+    len
+}
+
+fn read_groups(groups: &[u8]) -> u64 {
+    let mut v: u64 = 0;
+    for b in groups.iter().rev() {
+        v <<= 7;
+        v |= (b & 0x7f) as u64;
+    }
+
+    v
+}
+
+pub fn encode<W, R>(tp: TracePoint, writer: W) -> R
 where
-    W: for<'a> FnOnce(&'a [u8]),
+    W: for<'a> FnOnce(&'a [u8]) -> R,
 {
-    const MAX_TS_SIZE: usize = u32::BITS as usize / 7 + 1;
-    let mut outbuf = [0; MAX_TS_SIZE + 1];
+    let mut outbuf = [0; MAX_PACKET_SIZE];
+
+    let mut packet_len = 0;
+    let mut id = tp.id;
 
-    outbuf[0] = tp.id;
+    loop {
+        let part = (id & 0x7f) as u8;
+        id >>= 7;
+        if id == 0 {
+            outbuf[packet_len] = part;
+            packet_len += 1;
+            break;
+        }
+        outbuf[packet_len] = part | 0x80;
+        packet_len += 1;
+    }
 
-    let mut packet_len = 1;
     let mut delta_t = tp.delta_t;
 
     for i in 0..MAX_TS_SIZE {
@@ -29,46 +280,271 @@ where
     }
     outbuf[packet_len - 1] |= 0x80;
 
-    writer(&outbuf[..packet_len]);
+    writer(&outbuf[..packet_len])
 }
 
-#[cfg(feature = "std")]
+fn encode_control<W, R>(kind: u8, raw: u64, writer: W) -> R
+where
+    W: for<'a> FnOnce(&'a [u8]) -> R,
+{
+    let mut outbuf = [0; MAX_PACKET_SIZE];
+
+    outbuf[0] = CONTROL_ID;
+    outbuf[1] = kind;
+
+    let packet_len = 2 + write_groups(&mut outbuf[2..], raw);
+    outbuf[packet_len - 1] |= 0x80;
+
+    writer(&outbuf[..packet_len])
+}
+
+/// Encodes a value. It is attached to the next trace point in the stream.
+pub fn encode_value<W, R>(value: Value, writer: W) -> R
+where
+    W: for<'a> FnOnce(&'a [u8]) -> R,
+{
+    let (kind, raw) = value.to_raw();
+    encode_control(kind, raw as u64, writer)
+}
+
+/// Encodes a sequence packet. `seq` is a number of trace points emitted before it,
+/// which lets the receiver count trace points lost in between two sequence packets.
+pub fn encode_sequence<W, R>(seq: u16, writer: W) -> R
+where
+    W: for<'a> FnOnce(&'a [u8]) -> R,
+{
+    encode_control(KIND_SEQUENCE, (seq as u32 % SEQUENCE_MODULO) as u64, writer)
+}
+
+/// Encodes an overflow packet, which tells that `count` trace points were dropped
+/// at this place of the stream.
+pub fn encode_overflow<W, R>(count: u32, writer: W) -> R
+where
+    W: for<'a> FnOnce(&'a [u8]) -> R,
+{
+    encode_control(KIND_OVERFLOW, count as u64, writer)
+}
+
+/// Encodes an absolute timestamp. Deltas of the following trace points are relative to it.
+pub fn encode_timestamp<W, R>(timestamp: u64, writer: W) -> R
+where
+    W: for<'a> FnOnce(&'a [u8]) -> R,
+{
+    encode_control(KIND_TIMESTAMP, timestamp, writer)
+}
+
+/// Encodes build ID of the firmware, which lets the receiver check that it uses
+/// the right elf file to interpret the trace.
+pub fn encode_build_id<W, R>(build_id: u32, writer: W) -> R
+where
+    W: for<'a> FnOnce(&'a [u8]) -> R,
+{
+    encode_control(KIND_BUILD_ID, build_id as u64, writer)
+}
+
+/// Encodes a core packet. Following trace points and timestamps belong to this core,
+/// until the next core packet or reset.
+pub fn encode_core<W, R>(core: u8, writer: W) -> R
+where
+    W: for<'a> FnOnce(&'a [u8]) -> R,
+{
+    encode_control(KIND_CORE, core as u64, writer)
+}
+
+/// Encodes a marker, an arbitrary code which marks a moment in the trace
+pub fn encode_marker<W, R>(code: u32, delta_t: u32, writer: W) -> R
+where
+    W: for<'a> FnOnce(&'a [u8]) -> R,
+{
+    encode_control(KIND_MARKER, code as u64 | (delta_t as u64) << 32, writer)
+}
+
+/// Encodes a task switch, after which the core runs the given task
+pub fn encode_task_switch<W, R>(task: u32, delta_t: u32, writer: W) -> R
+where
+    W: for<'a> FnOnce(&'a [u8]) -> R,
+{
+    encode_control(
+        KIND_TASK_SWITCH,
+        task as u64 | (delta_t as u64) << 32,
+        writer,
+    )
+}
+
+/// Encodes an instance packet. The following trace point belongs to this instance
+/// of a traced async fn.
+pub fn encode_instance<W, R>(instance: u32, writer: W) -> R
+where
+    W: for<'a> FnOnce(&'a [u8]) -> R,
+{
+    encode_control(KIND_INSTANCE, instance as u64, writer)
+}
+
+/// Encodes a reset packet, which marks the beginning of a new trace.
+/// Legacy header is encoded exactly as a version 0 reset.
+pub fn encode_reset<W, R>(header: SessionHeader, writer: W) -> R
+where
+    W: for<'a> FnOnce(&'a [u8]) -> R,
+{
+    encode_control(KIND_RESET, header.to_raw(), writer)
+}
+
+fn decode(packet: &[u8]) -> Option<Packet> {
+    let mut id: u32 = 0;
+    let mut id_len = 0;
+
+    for (i, b) in packet.iter().take(MAX_ID_SIZE).enumerate() {
+        id |= ((b & 0x7f) as u32) << (7 * i);
+        if b & 0x80 == 0 {
+            id_len = i + 1;
+            break;
+        }
+    }
+
+    if id_len == 0 || id > u16::MAX as u32 || id_len >= packet.len() {
+        return None;
+    }
+
+    if id == CONTROL_ID as u32 {
+        return decode_control(&packet[id_len..]);
+    }
+
+    Some(Packet::TracePoint(TracePoint {
+        id: id as u16,
+        delta_t: read_groups(&packet[id_len..]) as u32,
+    }))
+}
+
+// Original encoding, where the trace point id is a single raw byte. Id 0 was used
+// for reset only, which is decoded as a control packet, so that the receiver notices
+// the session header of a newer target.
+fn decode_legacy(packet: &[u8]) -> Option<Packet> {
+    match packet {
+        [CONTROL_ID, ..] => decode(packet),
+        [id, delta_t @ ..] if !delta_t.is_empty() => Some(Packet::TracePoint(TracePoint {
+            id: *id as u16,
+            delta_t: read_groups(delta_t) as u32,
+        })),
+        _ => None,
+    }
+}
+
+fn decode_control(body: &[u8]) -> Option<Packet> {
+    let (kind, args) = body.split_first()?;
+
+    match kind & 0x7f {
+        KIND_RESET => Some(Packet::Reset(SessionHeader::from_raw(read_groups(args)))),
+        KIND_SEQUENCE if !args.is_empty() => Some(Packet::Sequence(
+            (read_groups(args) % SEQUENCE_MODULO as u64) as u16,
+        )),
+        KIND_OVERFLOW if !args.is_empty() => Some(Packet::Overflow(read_groups(args) as u32)),
+        KIND_TIMESTAMP if !args.is_empty() => Some(Packet::Timestamp(read_groups(args))),
+        KIND_BUILD_ID if !args.is_empty() => Some(Packet::BuildId(read_groups(args) as u32)),
+        KIND_CORE if !args.is_empty() => u8::try_from(read_groups(args)).ok().map(Packet::Core),
+        KIND_MARKER if !args.is_empty() => {
+            let raw = read_groups(args);
+            Some(Packet::Marker {
+                code: raw as u32,
+                delta_t: (raw >> 32) as u32,
+            })
+        }
+        KIND_TASK_SWITCH if !args.is_empty() => {
+            let raw = read_groups(args);
+            Some(Packet::TaskSwitch {
+                task: raw as u32,
+                delta_t: (raw >> 32) as u32,
+            })
+        }
+        KIND_INSTANCE if !args.is_empty() => Some(Packet::Instance(read_groups(args) as u32)),
+        kind if !args.is_empty() => {
+            Value::from_raw(kind, read_groups(args) as u32).map(Packet::Value)
+        }
+        _ => None,
+    }
+}
+
+/// Statistics of stream corruption, collected by [Decoder]
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct DecoderStats {
+    /// Number of successfully decoded packets
+    pub packets: u64,
+    /// Number of packets, which were too long or malformed
+    pub invalid_packets: u64,
+    /// Number of bytes in invalid packets
+    pub discarded_bytes: u64,
+}
+
+/// Splits a byte stream into packets. It does not allocate and keeps at most
+/// [MAX_PACKET_SIZE] bytes, so it can be used on a target as well. Packets longer
+/// than that are discarded, and the decoder resyncs on the next packet boundary.
 pub struct Decoder {
-    queue: std::collections::VecDeque<u8>,
+    buf: [u8; MAX_PACKET_SIZE],
+    // Length of the current packet, might exceed the buffer size
+    len: usize,
+    prev_byte: Option<u8>,
+    version: u8,
+    stats: DecoderStats,
 }
 
-#[cfg(feature = "std")]
 impl Decoder {
-    pub fn new() -> Self {
+    pub const fn new() -> Self {
         Decoder {
-            queue: std::collections::VecDeque::new(),
+            buf: [0; MAX_PACKET_SIZE],
+            len: 0,
+            prev_byte: None,
+            version: PROTOCOL_VERSION,
+            stats: DecoderStats {
+                packets: 0,
+                invalid_packets: 0,
+                discarded_bytes: 0,
+            },
         }
     }
 
-    pub fn push_byte(&mut self, byte: u8) -> Option<TracePoint> {
-        let prev_byte = self.queue.back().cloned();
-        self.queue.push_back(byte);
+    pub fn push_byte(&mut self, byte: u8) -> Option<Packet> {
+        let prev_byte = self.prev_byte.replace(byte);
+
+        if self.len < MAX_PACKET_SIZE {
+            self.buf[self.len] = byte;
+        }
+        self.len = self.len.saturating_add(1);
 
         if let Some(prev_byte) = prev_byte {
             if (prev_byte & 0x80 == 0) && (byte & 0x80 != 0) {
-                let packet: Vec<_> = self.queue.drain(..).collect();
-                let id = packet[0];
-
-                let mut delta_t: u32 = 0;
-                for b in packet[1..].iter().rev() {
-                    delta_t <<= 7;
-                    delta_t |= (b & 0x7f) as u32;
+                let packet = match self.len {
+                    len if len > MAX_PACKET_SIZE => None,
+                    len if self.version == 0 => decode_legacy(&self.buf[..len]),
+                    len => decode(&self.buf[..len]),
+                };
+
+                if packet.is_some() {
+                    self.stats.packets += 1;
+                } else {
+                    self.stats.invalid_packets += 1;
+                    self.stats.discarded_bytes += self.len as u64;
                 }
 
-                return Some(TracePoint { id, delta_t });
+                self.len = 0;
+                self.prev_byte = None;
+
+                return packet;
             }
         }
 
         None
     }
+
+    pub fn stats(&self) -> DecoderStats {
+        self.stats
+    }
+
+    /// Sets the encoding version of the following packets, as announced by
+    /// the session header. Versions up to [PROTOCOL_VERSION] are supported.
+    pub fn set_version(&mut self, version: u8) {
+        self.version = version;
+    }
 }
 
-#[cfg(feature = "std")]
 impl Default for Decoder {
     fn default() -> Self {
         Decoder::new()
@@ -84,17 +560,76 @@ mod test {
     use super::*;
 
     prop_compose! {
-        fn arb_tracepoint()(id in any::<u8>(), delta_t in 0u32..(1<<28-1)) -> TracePoint {
+        fn arb_tracepoint()(id in 1..=u16::MAX, delta_t in any::<u32>()) -> TracePoint {
             TracePoint {id, delta_t}
         }
     }
 
+    fn arb_value() -> impl Strategy<Value = Value> {
+        prop_oneof![
+            any::<u8>().prop_map(Value::U8),
+            any::<u16>().prop_map(Value::U16),
+            any::<u32>().prop_map(Value::U32),
+            any::<i32>().prop_map(Value::I32),
+            (-1e9f32..1e9f32).prop_map(Value::F32),
+        ]
+    }
+
+    fn arb_packet() -> impl Strategy<Value = Packet> {
+        prop_oneof![
+            arb_tracepoint().prop_map(Packet::TracePoint),
+            arb_value().prop_map(Packet::Value),
+            (0..SEQUENCE_MODULO as u16).prop_map(Packet::Sequence),
+            any::<u32>().prop_map(Packet::Overflow),
+            any::<u64>().prop_map(Packet::Timestamp),
+            any::<u32>().prop_map(Packet::BuildId),
+            any::<u8>().prop_map(Packet::Core),
+            (any::<u32>(), any::<u32>())
+                .prop_map(|(code, delta_t)| Packet::Marker { code, delta_t }),
+            (any::<u32>(), any::<u32>())
+                .prop_map(|(task, delta_t)| Packet::TaskSwitch { task, delta_t }),
+            any::<u32>().prop_map(Packet::Instance),
+            Just(Packet::Reset(SessionHeader::LEGACY)),
+            (1..=u8::MAX, any::<u8>(), any::<u16>()).prop_map(
+                |(version, timestamp_bits, flags)| {
+                    Packet::Reset(SessionHeader {
+                        version,
+                        timestamp_bits,
+                        flags,
+                    })
+                }
+            ),
+        ]
+    }
+
+    fn encode_packet(pkt: Packet, stream: &mut Vec<u8>) {
+        match pkt {
+            Packet::TracePoint(tp) => encode(tp, |b| stream.extend_from_slice(b)),
+            Packet::Value(v) => encode_value(v, |b| stream.extend_from_slice(b)),
+            Packet::Sequence(seq) => encode_sequence(seq, |b| stream.extend_from_slice(b)),
+            Packet::Overflow(count) => encode_overflow(count, |b| stream.extend_from_slice(b)),
+            Packet::Timestamp(ts) => encode_timestamp(ts, |b| stream.extend_from_slice(b)),
+            Packet::BuildId(id) => encode_build_id(id, |b| stream.extend_from_slice(b)),
+            Packet::Core(core) => encode_core(core, |b| stream.extend_from_slice(b)),
+            Packet::Marker { code, delta_t } => {
+                encode_marker(code, delta_t, |b| stream.extend_from_slice(b))
+            }
+            Packet::TaskSwitch { task, delta_t } => {
+                encode_task_switch(task, delta_t, |b| stream.extend_from_slice(b))
+            }
+            Packet::Instance(instance) => {
+                encode_instance(instance, |b| stream.extend_from_slice(b))
+            }
+            Packet::Reset(header) => encode_reset(header, |b| stream.extend_from_slice(b)),
+        }
+    }
+
     proptest! {
         #[test]
-        fn single_enc_dec(pkt in arb_tracepoint()) {
+        fn single_enc_dec(pkt in arb_packet()) {
 
             let mut stream = Vec::new();
-            encode(pkt, |b| stream.extend_from_slice(b));
+            encode_packet(pkt, &mut stream);
 
             let mut dec = Decoder::new();
 
@@ -108,11 +643,11 @@ mod test {
         }
 
         #[test]
-        fn multi_enc_dec(pkts in prop::collection::vec(arb_tracepoint(), 1..1000)) {
+        fn multi_enc_dec(pkts in prop::collection::vec(arb_packet(), 1..1000)) {
 
             let mut stream = Vec::new();
             for pkt in pkts.iter() {
-                encode(*pkt, |b| stream.extend_from_slice(b));
+                encode_packet(*pkt, &mut stream);
             }
 
             let mut dec = Decoder::new();
@@ -130,14 +665,14 @@ mod test {
         }
 
         #[test]
-        fn multi_enc_dec_with_resync(pkts in prop::collection::vec(arb_tracepoint(), 1..1000),
+        fn multi_enc_dec_with_resync(pkts in prop::collection::vec(arb_packet(), 1..1000),
                                     junk in prop::collection::vec(any::<u8>(), 1..1000)) {
 
             let mut stream = junk;
 
-            encode(TracePoint {id: 0, delta_t: 0}, |b| stream.extend_from_slice(b));
+            encode_reset(SessionHeader::default(), |b| stream.extend_from_slice(b));
             for pkt in pkts.iter() {
-                encode(*pkt, |b| stream.extend_from_slice(b));
+                encode_packet(*pkt, &mut stream);
             }
 
             let mut dec = Decoder::new();
@@ -191,7 +726,7 @@ mod test {
             encode(p, |b| serialized.extend_from_slice(b));
         }
 
-        let mut deserialized: Vec<TracePoint> = Vec::new();
+        let mut deserialized: Vec<Packet> = Vec::new();
 
         let mut dec = Decoder::new();
 
@@ -206,8 +741,112 @@ mod test {
         assert_eq!(deserialized.len(), in_packets.len());
 
         for (out, inp) in zip(deserialized.iter(), in_packets.iter()) {
-            assert_eq!(out, inp);
+            assert_eq!(*out, Packet::TracePoint(*inp));
+        }
+    }
+
+    #[test]
+    fn test_id_length() {
+        let cases = [
+            (1u16, 3usize),
+            (127, 3),
+            (128, 4),
+            (16383, 4),
+            (16384, 5),
+            (u16::MAX, 5),
+        ];
+
+        for (id, len) in cases {
+            let mut serialized: Vec<u8> = Vec::new();
+            encode(TracePoint { id, delta_t: 0 }, |b| {
+                serialized.extend_from_slice(b)
+            });
+            assert_eq!(serialized.len(), len);
+
+            let mut dec = Decoder::new();
+            let decoded = serialized
+                .into_iter()
+                .filter_map(|b| dec.push_byte(b))
+                .last();
+            assert_eq!(
+                decoded,
+                Some(Packet::TracePoint(TracePoint { id, delta_t: 0 }))
+            );
+        }
+    }
+
+    #[test]
+    fn test_overlong_packet() {
+        let first = TracePoint { id: 1, delta_t: 1 };
+        let second = TracePoint { id: 2, delta_t: 2 };
+
+        // Junk without packet boundaries merges with the first packet
+        let mut serialized: Vec<u8> = vec![0x01; 3 * MAX_PACKET_SIZE];
+        encode(first, |b| serialized.extend_from_slice(b));
+        let first_len = serialized.len();
+        encode(second, |b| serialized.extend_from_slice(b));
+
+        let mut dec = Decoder::new();
+        let decoded: Vec<_> = serialized
+            .into_iter()
+            .filter_map(|b| dec.push_byte(b))
+            .collect();
+        assert_eq!(decoded, [Packet::TracePoint(second)]);
+        assert_eq!(
+            dec.stats(),
+            DecoderStats {
+                packets: 1,
+                invalid_packets: 1,
+                discarded_bytes: first_len as u64,
+            }
+        );
+    }
+
+    #[test]
+    fn test_legacy_decoding() {
+        // Original encoding with a raw id byte, which might have the high bit set
+        let serialized = [0x00, 0x00, 0x80, 0x85, 0x03, 0x80, 0x07, 0x01, 0x81];
+
+        let mut dec = Decoder::new();
+        let mut decoded = Vec::new();
+        for b in serialized {
+            if let Some(p) = dec.push_byte(b) {
+                if let Packet::Reset(header) = p {
+                    dec.set_version(header.version);
+                }
+                decoded.push(p);
+            }
         }
+
+        assert_eq!(
+            decoded,
+            [
+                Packet::Reset(SessionHeader::LEGACY),
+                Packet::TracePoint(TracePoint {
+                    id: 0x85,
+                    delta_t: 3
+                }),
+                Packet::TracePoint(TracePoint {
+                    id: 7,
+                    delta_t: 1 | 1 << 7
+                }),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_reset_compat() {
+        // Reset used to be encoded as a trace point with id 0 and zero delta
+        let mut serialized: Vec<u8> = Vec::new();
+        encode_reset(SessionHeader::LEGACY, |b| serialized.extend_from_slice(b));
+        assert_eq!(serialized, [0x00, 0x00, 0x80]);
+
+        let mut dec = Decoder::new();
+        let decoded = serialized
+            .into_iter()
+            .filter_map(|b| dec.push_byte(b))
+            .last();
+        assert_eq!(decoded, Some(Packet::Reset(SessionHeader::LEGACY)));
     }
 }
 
@@ -216,7 +855,7 @@ mod verification {
     use super::*;
 
     #[kani::proof]
-    #[kani::unwind(6)]
+    #[kani::unwind(9)]
     pub fn check_encoding_length() {
         let mut serialized = Vec::new();
         let tp = TracePoint {
@@ -234,6 +873,16 @@ mod verification {
         kani::cover!(out_len == 4);
         kani::cover!(out_len == 5);
         kani::cover!(out_len == 6);
+        kani::cover!(out_len == 7);
+        kani::cover!(out_len == 8);
+
+        if tp.id >= 1 << 7 {
+            req_len += 1;
+        }
+
+        if tp.id >= 1 << 14 {
+            req_len += 1;
+        }
 
         if tp.delta_t >= 1 << 14 {
             req_len += 1;
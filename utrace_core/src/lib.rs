@@ -1,9 +1,12 @@
 #![cfg_attr(not(feature = "std"), no_std)]
 
+pub mod build_id;
+pub mod command;
 pub mod encoding;
 
 #[cfg(feature = "std")]
 pub mod trace_point;
 
-pub const MAX_TRACE_POINTS: usize = 255;
+pub const MAX_TRACE_POINTS: usize = u16::MAX as usize;
 pub const TRACE_POINT_SECTION_NAME: &str = ".utrace_trace_points";
+pub const TIMESTAMP_INFO_SECTION_NAME: &str = ".utrace_timestamp";
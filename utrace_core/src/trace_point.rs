@@ -1,22 +1,30 @@
 use anyhow::{Context, Result};
 use serde::{Deserialize, Serialize};
 
-pub type TracePointId = u8;
+pub type TracePointId = u16;
 
 /// Kind of enter/exit point pairs
 /// - AsyncInstantiation points are emited on enrty and/or exit from instrumentated async fns,
 ///   hence they cover the lifecycle of async fn from initial call till Future resolution
 /// - AsyncPoll point pair covers the execution of respective Future poll function calls
 /// - Generic points are emited when instrumentation is inserted by trace_here! macro
+/// - Isr points are emited on entry and exit of interrupt handlers, instrumented with `#[trace_isr]`
 #[derive(PartialEq, Eq, Debug, Clone, Copy)]
 pub enum TracePointPairKind {
     SyncCall,
     AsyncInstantiation,
     AsyncPoll,
     Generic,
+    Isr,
 }
 
 /// Kind of specific point trace instrumentation point
+/// - Value points are emited by trace_value! macro and carry a value instead of
+///   being a part of enter/exit pair
+/// - Counter points are emited by counter! macro and carry a sample of a numeric signal
+/// - Log points are emited by log! macro and carry the arguments of the format string
+/// - Panic point is emited by utrace::panic_hook and carries the location of the panic
+/// - IsrEnter point carries the active priority level of the interrupt, if it is given
 #[derive(PartialEq, Eq, Debug, Clone, Copy, Hash, Serialize, Deserialize)]
 pub enum TracePointKind {
     SyncEnter,
@@ -27,6 +35,12 @@ pub enum TracePointKind {
     AsyncPollExit,
     GenericEnter,
     GenericExit,
+    Value,
+    Counter,
+    Log,
+    Panic,
+    IsrEnter,
+    IsrExit,
 }
 
 #[derive(PartialEq, Eq, Debug, Clone, Hash, Serialize, Deserialize)]
@@ -36,6 +50,20 @@ pub struct TracePointInfo {
     pub comment: Option<String>,
     pub skip: Option<u32>,
     pub id: u64,
+    /// Format string of log points
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub format: Option<String>,
+    /// Minimum interval between reported calls. The trace point carries the number
+    /// of calls, suppressed since the previous report
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_interval_us: Option<u32>,
+}
+
+/// Time base of the timestamp function, declared with `#[utrace::timestamp]`
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Hash, Serialize, Deserialize)]
+pub struct TimestampInfo {
+    /// Number of timestamp ticks per second
+    pub freq_hz: u64,
 }
 
 #[derive(Debug, Hash)]
@@ -52,32 +80,62 @@ impl TracePointKind {
             TracePointKind::SyncEnter
             | TracePointKind::AsyncEnter
             | TracePointKind::AsyncPollEnter
-            | TracePointKind::GenericEnter => true,
+            | TracePointKind::GenericEnter
+            | TracePointKind::IsrEnter => true,
             TracePointKind::SyncExit
             | TracePointKind::AsyncExit
             | TracePointKind::AsyncPollExit
-            | TracePointKind::GenericExit => false,
+            | TracePointKind::GenericExit
+            | TracePointKind::IsrExit
+            | TracePointKind::Value
+            | TracePointKind::Counter
+            | TracePointKind::Log
+            | TracePointKind::Panic => false,
         }
     }
 
     pub fn is_exit(&self) -> bool {
-        !self.is_enter()
+        match self {
+            TracePointKind::SyncExit
+            | TracePointKind::AsyncExit
+            | TracePointKind::AsyncPollExit
+            | TracePointKind::GenericExit
+            | TracePointKind::IsrExit => true,
+            TracePointKind::SyncEnter
+            | TracePointKind::AsyncEnter
+            | TracePointKind::AsyncPollEnter
+            | TracePointKind::GenericEnter
+            | TracePointKind::IsrEnter
+            | TracePointKind::Value
+            | TracePointKind::Counter
+            | TracePointKind::Log
+            | TracePointKind::Panic => false,
+        }
     }
 }
 
-impl From<TracePointKind> for TracePointPairKind {
-    fn from(v: TracePointKind) -> TracePointPairKind {
+impl TryFrom<TracePointKind> for TracePointPairKind {
+    type Error = TracePointKind;
+
+    fn try_from(v: TracePointKind) -> Result<TracePointPairKind, TracePointKind> {
         match v {
-            TracePointKind::SyncEnter | TracePointKind::SyncExit => TracePointPairKind::SyncCall,
+            TracePointKind::SyncEnter | TracePointKind::SyncExit => {
+                Ok(TracePointPairKind::SyncCall)
+            }
             TracePointKind::AsyncEnter | TracePointKind::AsyncExit => {
-                TracePointPairKind::AsyncInstantiation
+                Ok(TracePointPairKind::AsyncInstantiation)
             }
             TracePointKind::AsyncPollEnter | TracePointKind::AsyncPollExit => {
-                TracePointPairKind::AsyncPoll
+                Ok(TracePointPairKind::AsyncPoll)
             }
             TracePointKind::GenericEnter | TracePointKind::GenericExit => {
-                TracePointPairKind::Generic
+                Ok(TracePointPairKind::Generic)
             }
+            TracePointKind::IsrEnter | TracePointKind::IsrExit => Ok(TracePointPairKind::Isr),
+            TracePointKind::Value
+            | TracePointKind::Counter
+            | TracePointKind::Log
+            | TracePointKind::Panic => Err(v),
         }
     }
 }
@@ -89,6 +147,7 @@ impl TracePointPairKind {
             TracePointPairKind::AsyncInstantiation => TracePointKind::AsyncEnter,
             TracePointPairKind::AsyncPoll => TracePointKind::AsyncPollEnter,
             TracePointPairKind::Generic => TracePointKind::GenericEnter,
+            TracePointPairKind::Isr => TracePointKind::IsrEnter,
         }
     }
 
@@ -98,6 +157,7 @@ impl TracePointPairKind {
             TracePointPairKind::AsyncInstantiation => TracePointKind::AsyncExit,
             TracePointPairKind::AsyncPoll => TracePointKind::AsyncPollExit,
             TracePointPairKind::Generic => TracePointKind::GenericExit,
+            TracePointPairKind::Isr => TracePointKind::IsrExit,
         }
     }
 }
@@ -119,6 +179,64 @@ impl TracePointInfo {
     }
 }
 
+impl TimestampInfo {
+    pub fn to_escaped_string(&self) -> String {
+        let serialized =
+            serde_json::to_string(self).expect("Internal error during TimestampInfo serialization");
+        escape(&serialized)
+    }
+
+    pub fn from_mangled_string<T>(s: T) -> Result<Self>
+    where
+        T: AsRef<str>,
+    {
+        let serialized = unescape(s.as_ref());
+        serde_json::from_str::<Self>(&serialized)
+            .context("Malformed JSON deserialization attempt for TimestampInfo")
+    }
+
+    /// Converts timestamp ticks to microseconds
+    pub fn to_micros(&self, ticks: u64) -> f64 {
+        ticks as f64 * 1_000_000.0 / self.freq_hz as f64
+    }
+}
+
+/// Timestamps are assumed to be in microseconds, unless declared otherwise
+impl Default for TimestampInfo {
+    fn default() -> Self {
+        TimestampInfo { freq_hz: 1_000_000 }
+    }
+}
+
+/// Splits a log format string into literal pieces around `{}` placeholders, so
+/// there is one piece more than placeholders. `{{` and `}}` stand for literal braces.
+pub fn split_format(format: &str) -> Result<Vec<String>, String> {
+    let mut pieces = vec![String::new()];
+    let mut chars = format.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match (c, chars.peek()) {
+            ('{', Some('{')) | ('}', Some('}')) => {
+                chars.next();
+                pieces.last_mut().unwrap().push(c);
+            }
+            ('{', Some('}')) => {
+                chars.next();
+                pieces.push(String::new());
+            }
+            ('{', _) | ('}', _) => {
+                return Err(format!(
+                    "Invalid format string {:?}: only {{}} placeholders are supported",
+                    format
+                ))
+            }
+            (c, _) => pieces.last_mut().unwrap().push(c),
+        }
+    }
+
+    Ok(pieces)
+}
+
 fn escape(inp: &str) -> String {
     inp.to_owned()
 }
@@ -140,6 +258,30 @@ impl fmt::Display for TracePointKind {
             TracePointKind::AsyncPollExit => write!(f, "AsyncPollExit"),
             TracePointKind::GenericEnter => write!(f, "GenericEnter"),
             TracePointKind::GenericExit => write!(f, "GenericExit"),
+            TracePointKind::Value => write!(f, "Value"),
+            TracePointKind::Counter => write!(f, "Counter"),
+            TracePointKind::Log => write!(f, "Log"),
+            TracePointKind::Panic => write!(f, "Panic"),
+            TracePointKind::IsrEnter => write!(f, "IsrEnter"),
+            TracePointKind::IsrExit => write!(f, "IsrExit"),
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn timestamp_to_micros() {
+        assert_eq!(TimestampInfo::default().to_micros(1500), 1500.0);
+        assert_eq!(
+            TimestampInfo {
+                freq_hz: 64_000_000
+            }
+            .to_micros(640),
+            10.0
+        );
+        assert_eq!(TimestampInfo { freq_hz: 1_000 }.to_micros(3), 3000.0);
+    }
+}
@@ -11,62 +11,116 @@ fn location_hash() -> u64 {
     std::hash::Hasher::finish(&hasher)
 }
 
+/// Which calls of a traced span are reported
+#[derive(Clone, Copy, Default)]
+pub struct Sampling {
+    /// Each Nth call
+    pub skip: Option<u32>,
+    /// Calls, which are at least this far from the previous reported one
+    pub min_interval_us: Option<u32>,
+}
+
 fn trace_point_definition(
     kind: TracePointKind,
     name: Option<String>,
     comment: Option<String>,
-    skip: Option<u32>,
+    sampling: Sampling,
 ) -> TokenStream {
-    let tp = TracePointInfo {
+    trace_point_info_definition(TracePointInfo {
         kind,
         name,
         comment,
-        skip,
+        skip: sampling.skip,
         id: location_hash(),
-    };
+        format: None,
+        min_interval_us: sampling.min_interval_us,
+    })
+}
 
+fn trace_point_info_definition(tp: TracePointInfo) -> TokenStream {
     let tp_str = tp.to_escaped_string();
+    let tp_hash = utrace_core::build_id::metadata_hash(tp_str.as_bytes());
 
     quote! {{
         #[link_section = "utrace_trace_points"]
         #[export_name=#tp_str]
         static ENTRY_ID_HOLDER: u8 = 0;
-        &ENTRY_ID_HOLDER as *const u8 as u8
+
+        #[link_section = "utrace_build_id"]
+        #[used]
+        static BUILD_ID_ENTRY: utrace::tracer::BuildIdEntry = utrace::tracer::BuildIdEntry {
+            hash: #tp_hash,
+            id: &ENTRY_ID_HOLDER,
+        };
+
+        &ENTRY_ID_HOLDER as *const u8 as u16
     }}
 }
 
+/// How the tracer relates to instances of an async fn
+#[derive(Clone, Copy, PartialEq)]
+pub enum Instance {
+    /// Not an async fn
+    None,
+    /// Creation of an instance, which decides whether it is sampled
+    Future,
+    /// Poll of an instance, which is traced only if the instance is sampled
+    Poll,
+}
+
 pub fn tracer_instantiation(
     tracer_kind: TracePointPairKind,
     name: Option<String>,
     comment: Option<String>,
-    skip: Option<u32>,
+    sampling: Sampling,
     enable_entry: bool,
     enable_exit: bool,
+    instance: Instance,
 ) -> TokenStream {
     let enter_kind = tracer_kind.enter_point();
     let exit_kind = tracer_kind.exit_point();
 
     let entry_def = if enable_entry {
-        let tpd = trace_point_definition(enter_kind, name.clone(), comment.clone(), skip);
+        let tpd = trace_point_definition(enter_kind, name.clone(), comment.clone(), sampling);
         quote! { Some(#tpd) }
     } else {
         quote! { None }
     };
 
     let exit_def = if enable_exit {
-        let tpd = trace_point_definition(exit_kind, name, comment, skip);
+        let tpd = trace_point_definition(exit_kind, name, comment, sampling);
         quote! {Some(#tpd) }
     } else {
         quote! { None }
     };
 
-    let skip_def = if let Some(skip) = skip {
+    let skip_def = if let (Some(skip), Instance::Poll) = (sampling.skip, instance) {
+        // Polls of each future are counted separately
+        quote! { utrace::tracer::SkipConfig::Skip {
+            counter: &utrace_poll_counter,
+            limit: #skip,
+         }
+        }
+    } else if let Some(skip) = sampling.skip {
         quote! { utrace::tracer::SkipConfig::Skip {
-            counter: {  static mut TRACE_COUNTER: u32 = 0;
-                        unsafe {&mut TRACE_COUNTER}},
+            counter: {
+                static TRACE_COUNTER: core::sync::atomic::AtomicU32 =
+                    core::sync::atomic::AtomicU32::new(0);
+                &TRACE_COUNTER
+            },
             limit: #skip,
          }
         }
+    } else if let Some(interval_us) = sampling.min_interval_us {
+        quote! { utrace::tracer::SkipConfig::MinInterval {
+            state: {
+                static TRACE_INTERVAL: utrace::tracer::IntervalState =
+                    utrace::tracer::IntervalState::new();
+                &TRACE_INTERVAL
+            },
+            interval_us: #interval_us,
+         }
+        }
     } else {
         quote! { utrace::tracer::SkipConfig::NoSkip }
     };
@@ -74,8 +128,27 @@ pub fn tracer_instantiation(
     let tracer_id = syn::Ident::from_string(&format!("_utrace_tracer_{}", location_hash()))
         .expect("Internal problem during tracer instantiation site generation");
 
+    match instance {
+        Instance::None => quote! {
+            let #tracer_id = utrace::tracer::Tracer::new(#entry_def, #exit_def, #skip_def, None)
+        },
+        Instance::Future => quote! {
+            let (#tracer_id, utrace_instance) =
+                utrace::tracer::Tracer::new_instance(#entry_def, #exit_def, #skip_def)
+        },
+        Instance::Poll => quote! {
+            let #tracer_id = utrace_instance.and_then(|instance| {
+                utrace::tracer::Tracer::new(#entry_def, #exit_def, #skip_def, Some(instance))
+            })
+        },
+    }
+}
+
+pub fn value_emission(kind: TracePointKind, name: String, value: syn::Expr) -> TokenStream {
+    let tpd = trace_point_definition(kind, Some(name), None, Sampling::default());
+
     quote! {
-        let #tracer_id = utrace::tracer::Tracer::new(#entry_def, #exit_def, #skip_def)
+        utrace::tracer::Tracer::value(#tpd, utrace::Value::from(#value))
     }
 }
 
@@ -92,11 +165,19 @@ pub fn transform_async_fn(
             TracePointPairKind::AsyncPoll,
             name.clone(),
             attrs.comment.clone(),
-            attrs.skip_poll,
+            Sampling {
+                skip: attrs.skip_poll,
+                min_interval_us: None,
+            },
             !attrs.noenter_poll,
             !attrs.noexit_poll,
+            Instance::Poll,
         );
+        let poll_counter = attrs.skip_poll.map(|_| {
+            quote! { let utrace_poll_counter = core::sync::atomic::AtomicU32::new(0); }
+        });
         quote! {
+            #poll_counter
             let mut utrace_instrumented_body = core::pin::pin!(async move #body);
             core::future::poll_fn(|cx| {
                 #poll_tracer;
@@ -107,14 +188,20 @@ pub fn transform_async_fn(
         body
     };
 
+    // Trace points of each instance of the future carry its id. If the creation
+    // of the instance is traced, it also decides whether the instance is sampled
     if trace_inst {
         let inst_tracer = tracer_instantiation(
             TracePointPairKind::AsyncInstantiation,
             name,
             attrs.comment,
-            attrs.skip,
+            Sampling {
+                skip: attrs.skip,
+                min_interval_us: attrs.min_interval_us,
+            },
             !attrs.noenter_fn,
             !attrs.noexit_fn,
+            Instance::Future,
         );
 
         quote! {
@@ -123,6 +210,11 @@ pub fn transform_async_fn(
                 #body
             }
         }
+    } else if trace_poll {
+        quote! {
+            let utrace_instance = Some(utrace::tracer::Tracer::next_instance());
+            #body
+        }
     } else {
         body
     }
@@ -140,9 +232,13 @@ pub fn transform_sync_fn(
             TracePointPairKind::SyncCall,
             name,
             attrs.comment,
-            attrs.skip,
+            Sampling {
+                skip: attrs.skip,
+                min_interval_us: attrs.min_interval_us,
+            },
             !attrs.noenter_fn,
             !attrs.noexit_fn,
+            Instance::None,
         );
 
         quote! {
@@ -155,3 +251,55 @@ pub fn transform_sync_fn(
         body
     }
 }
+
+pub fn transform_isr(
+    name: Option<String>,
+    attrs: crate::IsrAttributesMeta,
+    body: TokenStream,
+) -> TokenStream {
+    let sampling = Sampling::default();
+    let entry_def = trace_point_definition(
+        TracePointKind::IsrEnter,
+        name.clone(),
+        attrs.comment.clone(),
+        sampling,
+    );
+    let exit_def = trace_point_definition(TracePointKind::IsrExit, name, attrs.comment, sampling);
+
+    let priority_def = match attrs.priority {
+        Some(priority) => quote! { Some(utrace::Value::from(#priority)) },
+        None => quote! { None },
+    };
+
+    quote! {
+        let _utrace_isr_tracer = utrace::tracer::Tracer::isr(#entry_def, #exit_def, #priority_def);
+        {
+            #body
+        }
+    }
+}
+
+pub fn log_emission(format: String, args: Vec<syn::Expr>) -> TokenStream {
+    let tpd = trace_point_info_definition(TracePointInfo {
+        kind: TracePointKind::Log,
+        name: None,
+        comment: None,
+        skip: None,
+        id: location_hash(),
+        format: Some(format),
+        min_interval_us: None,
+    });
+
+    quote! {
+        utrace::tracer::Tracer::log(#tpd, &[#(utrace::Value::from(#args)),*])
+    }
+}
+
+pub fn panic_point_definition() -> TokenStream {
+    trace_point_definition(
+        TracePointKind::Panic,
+        Some("panic".to_owned()),
+        None,
+        Sampling::default(),
+    )
+}
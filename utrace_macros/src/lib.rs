@@ -4,6 +4,7 @@
 use darling::ast::NestedMeta;
 use darling::FromMeta;
 use quote::quote;
+use utrace_core::trace_point::TimestampInfo;
 
 mod codegen;
 
@@ -29,24 +30,92 @@ mod codegen;
 /// - `noexit` --- exit point of a stan will not be emited.
 /// - `skip=N` --- Report span entry and exit only each Nth time. Can be used to relief the transport
 ///   bandwidth requirement.
+/// - `min_interval=N` --- Report the span only if the previous reported one has started at least N
+///   microseconds ago. The number of suppressed spans is reported with the next one. Can not be combined
+///   with `skip`. It is also accepted as `min_interval_us=N`, as for [macro@trace].
 ///
 #[proc_macro]
 pub fn trace_here(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
     let attrs = NestedMeta::parse_meta_list(input.into()).expect("Malformed trace_here! arguments");
     let attrs = FreestandingMeta::from_list(&attrs).expect("Unable to parse trace_here! arguments");
 
+    let min_interval_us = match (attrs.min_interval, attrs.min_interval_us) {
+        (Some(_), Some(_)) => {
+            return quote! {
+                compile_error!("Attributes min_interval and min_interval_us are mutually exclusive");
+            }
+            .into();
+        }
+        (min_interval, min_interval_us) => min_interval.or(min_interval_us),
+    };
+
+    if attrs.skip.is_some() && min_interval_us.is_some() {
+        return quote! {
+            compile_error!("Attributes skip and min_interval are mutually exclusive");
+        }
+        .into();
+    }
+
     let ret = codegen::tracer_instantiation(
         utrace_core::trace_point::TracePointPairKind::Generic,
         None,
         attrs.comment,
-        attrs.skip,
+        codegen::Sampling {
+            skip: attrs.skip,
+            min_interval_us,
+        },
         !attrs.noenter,
         !attrs.noexit,
+        codegen::Instance::None,
     );
 
     quote! {#ret;}.into()
 }
 
+/// This macro reports a value, such as a queue depth or an error code, as an instant event.
+///
+/// For example:
+///
+/// ```ignore
+/// utrace::trace_value!("queue_depth", queue.len() as u16);
+/// ```
+///
+/// The first argument is a name of the value, the second one is an expression of one of
+/// the following types: `u8`, `u16`, `u32`, `i32` or `f32`.
+#[proc_macro]
+pub fn trace_value(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let args: ValueArgs = syn::parse(input).expect("Malformed trace_value! arguments");
+
+    codegen::value_emission(
+        utrace_core::trace_point::TracePointKind::Value,
+        args.name.value(),
+        args.value,
+    )
+    .into()
+}
+
+/// This macro reports a sample of a numeric signal, such as a buffer fill level or a battery
+/// voltage. Trace interpretation tool plots samples with the same name as a counter track.
+///
+/// For example:
+///
+/// ```ignore
+/// utrace::counter!("battery_mv", adc.read_battery());
+/// ```
+///
+/// Arguments and supported value types are the same as for [trace_value!].
+#[proc_macro]
+pub fn counter(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let args: ValueArgs = syn::parse(input).expect("Malformed counter! arguments");
+
+    codegen::value_emission(
+        utrace_core::trace_point::TracePointKind::Counter,
+        args.name.value(),
+        args.value,
+    )
+    .into()
+}
+
 /// This attribute can be applied to functions and async functions to instrument them.
 /// By default, when applied to a function, it will trace function entry and function exit.
 /// If applied to `async fn`, it will report creation, drop and poll spans of the respective Future.
@@ -61,13 +130,15 @@ pub fn trace_here(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
 /// - `noenter_poll` --- applicable to `async fn`. Disables tracing of Future `poll(..)` entry.
 /// - `noexit_poll` --- same as `noenter_poll`, but for `poll` exit.
 /// - `skip=N` --- Report function entry and exit (or Future creation/drop) only each Nth time. Can
-///   be used to releif the transport bandwidth requirement.
-/// - `skip_poll=N` --- same, but for poll function.
-///
-/// <div class="warning">
-/// Please note, that current implementation handles skip and skip_poll independent, so specifying
-/// skip will likely break Future lifetime tracing.
-/// </div>
+///   be used to releif the transport bandwidth requirement. For `async fn`, the decision is made once,
+///   when the Future is created: a sampled Future reports its creation, polls and drop, and other
+///   Futures report nothing.
+/// - `skip_poll=N` --- same as `skip`, but for poll function. Polls are counted separately for each
+///   Future, and only each Nth poll of a sampled Future is reported. Without it, every poll of a sampled
+///   Future is reported.
+/// - `min_interval_us=N` --- Report function entry and exit (or Future creation/drop) only if the previous
+///   reported one is at least N microseconds old. The number of suppressed calls is reported with the next
+///   one. Can not be combined with `skip`.
 ///
 /// Example:
 /// ```ignore
@@ -90,7 +161,11 @@ pub fn trace(
     let head_ident = &ast.sig;
     let fn_vis = &ast.vis;
     let body = &ast.block;
-    let body = if ast.sig.asyncness.is_some() {
+    let body = if attrs.skip.is_some() && attrs.min_interval_us.is_some() {
+        quote! {
+            compile_error!("Attributes skip and min_interval_us are mutually exclusive");
+        }
+    } else if ast.sig.asyncness.is_some() {
         codegen::transform_async_fn(Some(ast.sig.ident.to_string()), attrs, quote! {#body})
     } else if attrs.skip_poll.is_some() || attrs.noenter_poll || attrs.noexit_poll {
         quote! {
@@ -109,20 +184,117 @@ pub fn trace(
     expanded.into()
 }
 
+/// This attribute can be applied to interrupt handlers to report their entry and exit.
+/// Trace interpretation tool draws interrupts of each core on a separate track, so that
+/// the spans they preempt are not broken, and nests spans started by the handler into it.
+///
+/// This macro accepts following parameters:
+/// - `comment=S` --- optional string, which will be saved in the metadata for trace interpretation tool
+///   to inspect
+/// - `priority=EXPR` --- expression, evaluated on entry, which gives the active priority level of the
+///   interrupt. It should be of one of the types, supported by [trace_value!].
+///
+/// Example:
+/// ```ignore
+/// #[utrace::trace_isr(priority = NVIC::get_priority(Interrupt::UART0))]
+/// fn UART0() {
+///     ....
+/// }
+/// ```
+#[proc_macro_attribute]
+pub fn trace_isr(
+    attr: proc_macro::TokenStream,
+    input: proc_macro::TokenStream,
+) -> proc_macro::TokenStream {
+    let ast: syn::ItemFn = syn::parse(input).expect("Failed to parse input as a function");
+
+    let attrs = NestedMeta::parse_meta_list(attr.into()).expect("Malformed attr list");
+    let attrs = IsrAttributesMeta::from_list(&attrs)
+        .expect("Unable to parse #[trace_isr] attribute arguments");
+
+    let head_ident = &ast.sig;
+    let fn_vis = &ast.vis;
+    let fn_attrs = &ast.attrs;
+    let body = &ast.block;
+    let body = if ast.sig.asyncness.is_some() {
+        quote! {
+            compile_error!("#[trace_isr] cannot be applied to async functions");
+        }
+    } else {
+        codegen::transform_isr(Some(ast.sig.ident.to_string()), attrs, quote! {#body})
+    };
+
+    quote! {
+        #(#fn_attrs)*
+        #fn_vis #head_ident {
+            #body
+        }
+    }
+    .into()
+}
+
+/// This macro reports a log message as an instant event on the same timeline as the spans.
+///
+/// For example:
+///
+/// ```ignore
+/// utrace::log!("motor speed {} rpm, current {} mA", rpm, current_ma);
+/// ```
+///
+/// The format string is stored in the elf file, only the arguments are sent. Arguments
+/// are substituted into `{}` placeholders and should be of the types, supported by
+/// [trace_value!]. `{{` and `}}` stand for literal braces.
+#[proc_macro]
+pub fn log(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let args: LogArgs = syn::parse(input).expect("Malformed log! arguments");
+    let format = args.format.value();
+
+    let placeholders = match utrace_core::trace_point::split_format(&format) {
+        Ok(pieces) => pieces.len() - 1,
+        Err(e) => return quote! { compile_error!(#e) }.into(),
+    };
+
+    if placeholders != args.args.len() {
+        let e = format!(
+            "Format string has {} placeholders, but {} arguments are given",
+            placeholders,
+            args.args.len()
+        );
+        return quote! { compile_error!(#e) }.into();
+    }
+
+    codegen::log_emission(format, args.args.into_iter().collect()).into()
+}
+
+/// Defines the trace point, which is emitted by `utrace::panic_hook`, and evaluates to its id.
+/// Internal to utrace.
+#[doc(hidden)]
+#[proc_macro]
+pub fn panic_trace_point(_input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    codegen::panic_point_definition().into()
+}
+
 /// This macro provides a transport implementation for utrace.
 ///
 /// To create custom transport, one should do the following:
 ///
 /// ```ignore
 /// #[utrace::default_transport]
-/// fn transport(buf: &[u8]) {
+/// fn transport(buf: &[u8]) -> usize {
 ///    ...
 /// }
 /// ```
 ///
+/// The function should return the number of bytes, accepted by the transport. If only a part of
+/// a packet is accepted, its rest is passed to the transport before any other data, so that the
+/// stream stays intact. A packet, which is not accepted at all, is counted as dropped, and the
+/// number of dropped trace points is reported to the trace stream receiver once the transport
+/// has room for it.
+///
 /// Please note, that current implementation executes timestamp capture, serialization and
 /// sending in a single critical section, hence the transport function does not need to be
-/// reentrant.
+/// reentrant. With `buffered` feature, the transport function is called by `utrace::flush()`
+/// only, outside of critical sections, but still never reentrantly.
 ///
 /// Note, that during initialization you might want to call utrace::init().
 /// While this is not mandatory, this will provide a trace stream receiver with a point to
@@ -148,28 +320,109 @@ pub fn default_transport(
 /// For example:
 ///
 /// ```ignore
-/// #[utrace::timestamp]
+/// #[utrace::timestamp(freq_hz = 64_000_000)]
 /// fn timestamp() -> u64 {
 ///     ...
 /// }
 /// ```
 ///
+/// The time base is recorded in the elf file, so that the host tools can convert timestamps
+/// to real time. It is set either as `freq_hz`, the number of ticks per second, or as
+/// `unit`, one of `"s"`, `"ms"`, `"us"` and `"ns"`. If none is given, timestamps are assumed
+/// to be in microseconds.
+///
 /// It should only be invoked in binaries and only once.
 #[proc_macro_attribute]
 pub fn timestamp(
-    _attr: proc_macro::TokenStream,
+    attr: proc_macro::TokenStream,
     input: proc_macro::TokenStream,
 ) -> proc_macro::TokenStream {
     let body: syn::ItemFn =
         syn::parse(input).expect("#[utrace::timestamp] should be applied to a function");
 
+    let attrs = NestedMeta::parse_meta_list(attr.into()).expect("Malformed attr list");
+    let attrs =
+        TimestampMeta::from_list(&attrs).expect("Unable to parse #[timestamp] attribute arguments");
+
+    let freq_hz = match timestamp_freq_hz(&attrs) {
+        Ok(freq_hz) => freq_hz,
+        Err(e) => return quote! { compile_error!(#e); }.into(),
+    };
+
+    let info_def = freq_hz.map(|freq_hz| {
+        let info_str = TimestampInfo { freq_hz }.to_escaped_string();
+        quote! {
+            const _: () = {
+                #[link_section = "utrace_timestamp"]
+                #[export_name = #info_str]
+                #[used]
+                static TIMESTAMP_INFO_HOLDER: u8 = 0;
+            };
+        }
+    });
+
+    // Used by the firmware to convert min_interval_us of trace points into ticks
+    let target_freq_hz = freq_hz.unwrap_or(TimestampInfo::default().freq_hz);
+
     quote! {
+        #info_def
+
+        const _: () = {
+            #[export_name = "__utrace_timestamp_freq_hz"]
+            static TIMESTAMP_FREQ_HZ: u64 = #target_freq_hz;
+        };
+
         #[export_name = "__utrace_timestamp_function"]
         #body
     }
     .into()
 }
 
+// Time base of the timestamp function, if it is declared
+fn timestamp_freq_hz(attrs: &TimestampMeta) -> Result<Option<u64>, &'static str> {
+    match (attrs.freq_hz, attrs.unit.as_deref()) {
+        (None, None) => Ok(None),
+        (Some(0), None) => Err("freq_hz should be positive"),
+        (Some(freq_hz), None) => Ok(Some(freq_hz)),
+        (None, Some("s")) => Ok(Some(1)),
+        (None, Some("ms")) => Ok(Some(1_000)),
+        (None, Some("us")) => Ok(Some(1_000_000)),
+        (None, Some("ns")) => Ok(Some(1_000_000_000)),
+        (None, Some(_)) => Err("unit should be one of \"s\", \"ms\", \"us\", \"ns\""),
+        (Some(_), Some(_)) => Err("Attributes freq_hz and unit are mutually exclusive"),
+    }
+}
+
+/// This macro should be used to define a function, which returns id of the core
+/// executing it. It is required by `multicore` feature of utrace.
+///
+/// For example:
+///
+/// ```ignore
+/// #[utrace::core_id]
+/// fn core_id() -> u8 {
+///     ...
+/// }
+/// ```
+///
+/// Returned id should be less than `UTRACE_MAX_CORES` (2 by default). Events of
+/// the cores with larger ids are dropped and reported as lost.
+/// It should only be invoked in binaries and only once.
+#[proc_macro_attribute]
+pub fn core_id(
+    _attr: proc_macro::TokenStream,
+    input: proc_macro::TokenStream,
+) -> proc_macro::TokenStream {
+    let body: syn::ItemFn =
+        syn::parse(input).expect("#[utrace::core_id] should be applied to a function");
+
+    quote! {
+        #[export_name = "__utrace_core_id_function"]
+        #body
+    }
+    .into()
+}
+
 #[derive(Debug, FromMeta)]
 struct FnAttributesMeta {
     #[darling(default)]
@@ -186,6 +439,56 @@ struct FnAttributesMeta {
     skip: Option<u32>,
     #[darling(default)]
     skip_poll: Option<u32>,
+    #[darling(default)]
+    min_interval_us: Option<u32>,
+}
+
+#[derive(Debug, FromMeta)]
+struct IsrAttributesMeta {
+    #[darling(default)]
+    comment: Option<String>,
+    #[darling(default)]
+    priority: Option<syn::Expr>,
+}
+
+#[derive(Debug, FromMeta)]
+struct TimestampMeta {
+    #[darling(default)]
+    freq_hz: Option<u64>,
+    #[darling(default)]
+    unit: Option<String>,
+}
+
+struct ValueArgs {
+    name: syn::LitStr,
+    value: syn::Expr,
+}
+
+impl syn::parse::Parse for ValueArgs {
+    fn parse(input: syn::parse::ParseStream) -> syn::Result<Self> {
+        let name = input.parse()?;
+        input.parse::<syn::Token![,]>()?;
+        let value = input.parse()?;
+        Ok(ValueArgs { name, value })
+    }
+}
+
+struct LogArgs {
+    format: syn::LitStr,
+    args: syn::punctuated::Punctuated<syn::Expr, syn::Token![,]>,
+}
+
+impl syn::parse::Parse for LogArgs {
+    fn parse(input: syn::parse::ParseStream) -> syn::Result<Self> {
+        let format = input.parse()?;
+        let args = if input.is_empty() {
+            syn::punctuated::Punctuated::new()
+        } else {
+            input.parse::<syn::Token![,]>()?;
+            syn::punctuated::Punctuated::parse_terminated(input)?
+        };
+        Ok(LogArgs { format, args })
+    }
 }
 
 #[derive(Debug, FromMeta)]
@@ -198,4 +501,29 @@ struct FreestandingMeta {
     noexit: bool,
     #[darling(default)]
     skip: Option<u32>,
+    #[darling(default)]
+    min_interval: Option<u32>,
+    #[darling(default)]
+    min_interval_us: Option<u32>,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn freq_hz(attr: &str) -> Result<Option<u64>, &'static str> {
+        let attrs = NestedMeta::parse_meta_list(attr.parse().unwrap()).unwrap();
+        timestamp_freq_hz(&TimestampMeta::from_list(&attrs).unwrap())
+    }
+
+    #[test]
+    fn timestamp_time_base() {
+        assert_eq!(freq_hz(""), Ok(None));
+        assert_eq!(freq_hz("freq_hz = 64_000_000"), Ok(Some(64_000_000)));
+        assert_eq!(freq_hz("unit = \"ms\""), Ok(Some(1_000)));
+        assert_eq!(freq_hz("unit = \"ns\""), Ok(Some(1_000_000_000)));
+        assert!(freq_hz("freq_hz = 0").is_err());
+        assert!(freq_hz("unit = \"min\"").is_err());
+        assert!(freq_hz("freq_hz = 1000, unit = \"s\"").is_err());
+    }
 }
@@ -0,0 +1,17 @@
+#![no_std]
+//! Panic handler, which records the panic with its location in the trace stream,
+//! sends the trace, buffered in RAM, and halts. To use it, link the crate:
+//!
+//! ```ignore
+//! use utrace_panic as _;
+//! ```
+
+#[cfg(target_os = "none")]
+#[panic_handler]
+fn panic(info: &core::panic::PanicInfo) -> ! {
+    utrace::panic_hook(info);
+
+    loop {
+        core::sync::atomic::compiler_fence(core::sync::atomic::Ordering::SeqCst);
+    }
+}
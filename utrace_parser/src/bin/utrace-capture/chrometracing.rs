@@ -1,5 +1,5 @@
 use serde::Serialize;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::io::Write;
 use std::time::SystemTime;
 use std::time::UNIX_EPOCH;
@@ -7,6 +7,8 @@ use tokio::sync::broadcast::error::RecvError;
 use tokio::sync::broadcast::Receiver;
 use tracing::error;
 use tracing::warn;
+use utrace_core::encoding::Value;
+use utrace_core::trace_point::TimestampInfo;
 use utrace_parser::stream_parser::TimestampedTracepoint;
 
 #[derive(Serialize, PartialEq)]
@@ -17,6 +19,14 @@ enum EventType {
     SpanEnd,
     #[serde(rename = "i")]
     Instant,
+    #[serde(rename = "C")]
+    Counter,
+    #[serde(rename = "M")]
+    Metadata,
+    #[serde(rename = "b")]
+    AsyncBegin,
+    #[serde(rename = "e")]
+    AsyncEnd,
 }
 
 enum DrawingTypes {
@@ -40,7 +50,11 @@ struct Event {
     ty: EventType,
     pid: u32,
     tid: u32,
-    ts: u64,
+    ts: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    args: Option<serde_json::Value>,
+    #[serde(rename = "s", skip_serializing_if = "Option::is_none")]
+    scope: Option<String>,
 }
 
 #[derive(Serialize)]
@@ -51,22 +65,58 @@ struct ArrowEvent {
     ty: ArrowType,
     pid: u32,
     tid: u32,
-    ts: u64,
+    ts: f64,
     id: u32,
     bp: String,
 }
 
+// Each instance of a traced async fn is drawn as a separate async track
+#[derive(Serialize)]
+struct AsyncEvent {
+    name: String,
+    cat: String,
+    #[serde(rename = "ph")]
+    ty: EventType,
+    pid: u32,
+    tid: u32,
+    ts: f64,
+    id: u32,
+}
+
 struct TraceEntry {
-    last_timestamp: u64,
+    last_timestamp: f64,
     unique_id: u32,
 }
 
+// Tracks with tasks of the cores follow the tracks of trace points
+const TASK_TRACK_OFFSET: u32 = 1000;
+// Tracks with interrupts of the cores follow the tracks of tasks
+const ISR_TRACK_OFFSET: u32 = 2000;
+
 pub struct Store {
     hm: HashMap<u64, DrawingTypes>,
+    timestamp: TimestampInfo,
+}
+
+fn value_to_json(v: Value) -> serde_json::Value {
+    match v {
+        Value::U8(v) => v.into(),
+        Value::U16(v) => v.into(),
+        Value::U32(v) => v.into(),
+        Value::I32(v) => v.into(),
+        // Go through the shortest decimal representation, so that 3.3 is not shown as 3.2999999
+        Value::F32(v) => v.to_string().parse::<f64>().unwrap_or(v as f64).into(),
+    }
 }
 
 impl Store {
-    pub fn new(tp_map: &HashMap<u8, utrace_core::trace_point::TracePointDataWithLocation>) -> Self {
+    pub fn new(
+        tp_map: &HashMap<
+            utrace_core::trace_point::TracePointId,
+            utrace_core::trace_point::TracePointDataWithLocation,
+        >,
+        timestamp: TimestampInfo,
+    ) -> Self {
         let mut hm = HashMap::new();
 
         for tp in tp_map.values() {
@@ -75,6 +125,10 @@ impl Store {
             match tp.info.kind {
                 utrace_core::trace_point::TracePointKind::AsyncEnter => (),
                 utrace_core::trace_point::TracePointKind::AsyncExit => (),
+                utrace_core::trace_point::TracePointKind::Value => (),
+                utrace_core::trace_point::TracePointKind::Counter => (),
+                utrace_core::trace_point::TracePointKind::Log => (),
+                utrace_core::trace_point::TracePointKind::Panic => (),
                 _ => {
                     hm.entry(hash_id)
                         .and_modify(|w| *w = DrawingTypes::Span)
@@ -83,14 +137,22 @@ impl Store {
             }
         }
 
-        Store { hm }
+        Store { hm, timestamp }
     }
 
     pub async fn store<'a>(&self, fname: &str, mut chan: Receiver<TimestampedTracepoint<'a>>) {
-        let mut events: HashMap<String, TraceEntry> = HashMap::new();
+        // Futures are told apart by their instance, if the firmware reports it
+        let mut events: HashMap<(String, Option<u32>), TraceEntry> = HashMap::new();
         let mut unique_id_counter: u32 = 0;
 
         'reset_loop: loop {
+            let mut last_ts: f64 = 0.0;
+            let mut named_tracks = HashSet::new();
+            // Nesting depth of the interrupt handlers, running on each core
+            let mut active_isrs: HashMap<u8, u32> = HashMap::new();
+            let mut running_tasks: HashMap<u8, u32> = HashMap::new();
+            // Names of the spans, which are open on each track
+            let mut open_spans: HashMap<u32, Vec<String>> = HashMap::new();
             let start = SystemTime::now();
             let since_the_epoch = start
                 .duration_since(UNIX_EPOCH)
@@ -101,14 +163,16 @@ impl Store {
             if let Ok(mut file) = std::fs::OpenOptions::new()
                 .write(true)
                 .create(true)
+                .truncate(true)
                 .open(fname_ts)
             {
                 let _ = file.write_all(b"[ \n");
                 loop {
                     let msg = chan.recv().await;
                     if let Err(RecvError::Closed) = msg {
-                        warn!("Channel failure: {:?}", msg);
-                        break;
+                        // The source has finished, e.g. memory dump was read
+                        let _ = file.write_all(b"]");
+                        return;
                     } else if let Err(RecvError::Lagged(_)) = msg {
                         warn!("Channel lagged: {:?}", msg);
                         continue;
@@ -117,9 +181,58 @@ impl Store {
                     let msg = msg.unwrap();
                     match msg {
                         TimestampedTracepoint::Point {
-                            timestamp: ts,
+                            timestamp,
+                            core,
                             tracepoint: tp,
+                            value,
+                            instance,
                         } => {
+                            let ts = self.timestamp.to_micros(timestamp);
+                            last_ts = ts;
+
+                            // Interrupts and the spans they start are drawn on a separate track
+                            // of the core, so that the spans they preempt are not broken
+                            let is_isr = matches!(
+                                tp.info.kind,
+                                utrace_core::trace_point::TracePointKind::IsrEnter
+                                    | utrace_core::trace_point::TracePointKind::IsrExit
+                            );
+                            let isr_depth = active_isrs.entry(core).or_default();
+                            if tp.info.kind == utrace_core::trace_point::TracePointKind::IsrEnter {
+                                *isr_depth += 1;
+                            }
+                            let in_isr = is_isr || *isr_depth > 0;
+                            if tp.info.kind == utrace_core::trace_point::TracePointKind::IsrExit {
+                                *isr_depth = isr_depth.saturating_sub(1);
+                            }
+
+                            // Every core gets its own track
+                            let tid = if in_isr {
+                                ISR_TRACK_OFFSET + core as u32
+                            } else {
+                                core as u32 + 1
+                            };
+                            if named_tracks.insert(tid) {
+                                let (cat, track_name) = if in_isr {
+                                    ("Interrupt", format!("Core {} interrupts", core))
+                                } else {
+                                    ("Core", format!("Core {}", core))
+                                };
+                                let msg_out = Event {
+                                    name: "thread_name".to_owned(),
+                                    cat: cat.to_owned(),
+                                    ty: EventType::Metadata,
+                                    pid: 1,
+                                    tid,
+                                    ts: 0.0,
+                                    args: Some(serde_json::json!({ "name": track_name })),
+                                    scope: None,
+                                };
+                                let _ = file
+                                    .write_all(serde_json::to_string(&msg_out).unwrap().as_bytes());
+                                let _ = file.write_all(",\n".as_bytes());
+                            }
+
                             let mut arrow: Option<ArrowEvent> = None;
                             let mut arrow_type = ArrowType::ArrowStep;
                             let name = tp
@@ -128,34 +241,72 @@ impl Store {
                                 .clone()
                                 .or(tp.info.comment.clone())
                                 .unwrap_or(tp.info.id.to_string());
+                            let key = (name.clone(), instance);
 
-                            let event_type: EventType = if tp.info.kind.is_enter() {
+                            // Polls of a future are nested into its lifetime on the track of the instance
+                            let async_event = instance.and_then(|id| {
+                                let (ty, async_name) = match tp.info.kind {
+                                    utrace_core::trace_point::TracePointKind::AsyncEnter => {
+                                        (EventType::AsyncBegin, name.clone())
+                                    }
+                                    utrace_core::trace_point::TracePointKind::AsyncExit => {
+                                        (EventType::AsyncEnd, name.clone())
+                                    }
+                                    utrace_core::trace_point::TracePointKind::AsyncPollEnter => {
+                                        (EventType::AsyncBegin, "poll".to_owned())
+                                    }
+                                    utrace_core::trace_point::TracePointKind::AsyncPollExit => {
+                                        (EventType::AsyncEnd, "poll".to_owned())
+                                    }
+                                    _ => return None,
+                                };
+
+                                Some(AsyncEvent {
+                                    name: async_name,
+                                    cat: name.clone(),
+                                    ty,
+                                    pid: 1,
+                                    tid,
+                                    ts,
+                                    id,
+                                })
+                            });
+
+                            let event_type: EventType = if tp.info.kind
+                                == utrace_core::trace_point::TracePointKind::Value
+                            {
+                                EventType::Instant
+                            } else if tp.info.kind
+                                == utrace_core::trace_point::TracePointKind::Counter
+                            {
+                                EventType::Counter
+                            } else if tp.info.kind.is_enter() {
                                 let existing_event: &mut TraceEntry;
 
                                 // If new task execution, but prev was not dropped
-                                if events.contains_key(&name)
+                                if events.contains_key(&key)
                                     && tp.info.kind
                                         != utrace_core::trace_point::TracePointKind::AsyncPollEnter
                                 {
-                                    events.remove(&name);
+                                    events.remove(&key);
                                 }
 
-                                if events.contains_key(&name) {
-                                    existing_event = events.get_mut(&name).unwrap();
+                                if events.contains_key(&key) {
+                                    existing_event = events.get_mut(&key).unwrap();
 
                                     // If the event already exists, update the timestamp
                                     existing_event.last_timestamp = ts;
                                 } else {
                                     // New event, insert into the HashMap
                                     events.insert(
-                                        name.clone(),
+                                        key.clone(),
                                         TraceEntry {
                                             last_timestamp: ts,
                                             unique_id: unique_id_counter,
                                         },
                                     );
 
-                                    existing_event = events.get_mut(&name).unwrap();
+                                    existing_event = events.get_mut(&key).unwrap();
                                     arrow_type = ArrowType::ArrowStart;
 
                                     unique_id_counter += 1;
@@ -183,7 +334,7 @@ impl Store {
                                         cat: name.clone(),
                                         ty: arrow_type,
                                         pid: 1,
-                                        tid: 1,
+                                        tid,
                                         ts,
                                         id: existing_event.unique_id,
                                         bp: "e".to_owned(),
@@ -192,9 +343,9 @@ impl Store {
 
                                 ret_event_type
                             } else {
-                                let end_id = if events.contains_key(&name) {
-                                    events.get_mut(&name).unwrap().last_timestamp = ts;
-                                    events.get_mut(&name).unwrap().unique_id
+                                let end_id = if events.contains_key(&key) {
+                                    events.get_mut(&key).unwrap().last_timestamp = ts;
+                                    events.get_mut(&key).unwrap().unique_id
                                 } else {
                                     unique_id_counter + 1
                                 };
@@ -204,7 +355,7 @@ impl Store {
                                     cat: name.clone(),
                                     ty: ArrowType::ArrowStep,
                                     pid: 1,
-                                    tid: 1,
+                                    tid,
                                     ts,
                                     id: end_id,
                                     bp: "e".to_owned(),
@@ -212,7 +363,7 @@ impl Store {
 
                                 match tp.info.kind {
                                     utrace_core::trace_point::TracePointKind::AsyncExit => {
-                                        events.remove(&name);
+                                        events.remove(&key);
                                         EventType::Instant
                                     }
                                     _ => match self.hm.get(&tp.info.id) {
@@ -222,13 +373,40 @@ impl Store {
                                 }
                             };
 
+                            if event_type == EventType::SpanBegin {
+                                open_spans.entry(tid).or_default().push(name.clone());
+                            } else if event_type == EventType::SpanEnd {
+                                let spans = open_spans.entry(tid).or_default();
+                                if let Some(i) = spans.iter().rposition(|s| *s == name) {
+                                    spans.remove(i);
+                                }
+                            }
+
+                            let args = value.map(|v| {
+                                // Counter tracks are named after their args
+                                let arg_name = if event_type == EventType::Counter {
+                                    name.as_str()
+                                } else if tp.info.min_interval_us.is_some() {
+                                    "suppressed"
+                                } else if tp.info.kind
+                                    == utrace_core::trace_point::TracePointKind::IsrEnter
+                                {
+                                    "priority"
+                                } else {
+                                    "value"
+                                };
+                                serde_json::json!({ arg_name: value_to_json(v) })
+                            });
+
                             let msg_out = Event {
                                 name,
                                 cat: tp.info.kind.to_string(),
                                 ty: event_type,
                                 pid: 1,
-                                tid: 1,
+                                tid,
                                 ts,
+                                args,
+                                scope: None,
                             };
                             let _ =
                                 file.write_all(serde_json::to_string(&msg_out).unwrap().as_bytes());
@@ -240,6 +418,197 @@ impl Store {
                                 );
                                 let _ = file.write_all(",\n".as_bytes());
                             }
+
+                            if let Some(async_event) = async_event {
+                                let _ = file.write_all(
+                                    serde_json::to_string(&async_event).unwrap().as_bytes(),
+                                );
+                                let _ = file.write_all(",\n".as_bytes());
+                            }
+                        }
+
+                        TimestampedTracepoint::Log {
+                            timestamp,
+                            core,
+                            tracepoint: tp,
+                            message,
+                        } => {
+                            let ts = self.timestamp.to_micros(timestamp);
+                            last_ts = ts;
+
+                            let location = match (&tp.file_name, tp.line) {
+                                (Some(file_name), Some(line)) => {
+                                    Some(format!("{}:{}", file_name, line))
+                                }
+                                _ => None,
+                            };
+
+                            let msg_out = Event {
+                                name: message,
+                                cat: tp.info.kind.to_string(),
+                                ty: EventType::Instant,
+                                pid: 1,
+                                tid: core as u32 + 1,
+                                ts,
+                                args: location.map(|l| serde_json::json!({ "location": l })),
+                                scope: None,
+                            };
+                            let _ =
+                                file.write_all(serde_json::to_string(&msg_out).unwrap().as_bytes());
+                            let _ = file.write_all(",\n".as_bytes());
+                        }
+
+                        TimestampedTracepoint::Marker {
+                            timestamp,
+                            core,
+                            code,
+                        } => {
+                            let ts = self.timestamp.to_micros(timestamp);
+                            last_ts = ts;
+
+                            let msg_out = Event {
+                                name: format!("Marker {}", code),
+                                cat: "Marker".to_owned(),
+                                ty: EventType::Instant,
+                                pid: 1,
+                                tid: core as u32 + 1,
+                                ts,
+                                args: Some(serde_json::json!({ "code": code })),
+                                scope: Some("g".to_owned()),
+                            };
+                            let _ =
+                                file.write_all(serde_json::to_string(&msg_out).unwrap().as_bytes());
+                            let _ = file.write_all(",\n".as_bytes());
+                        }
+
+                        // Tasks of every core are drawn as spans on a separate track
+                        TimestampedTracepoint::TaskSwitch {
+                            timestamp,
+                            core,
+                            task,
+                        } => {
+                            let ts = self.timestamp.to_micros(timestamp);
+                            last_ts = ts;
+                            let tid = TASK_TRACK_OFFSET + core as u32;
+
+                            let mut msgs_out = Vec::new();
+                            match running_tasks.insert(core, task) {
+                                Some(prev_task) => msgs_out.push(Event {
+                                    name: format!("Task {}", prev_task),
+                                    cat: "Task".to_owned(),
+                                    ty: EventType::SpanEnd,
+                                    pid: 1,
+                                    tid,
+                                    ts,
+                                    args: None,
+                                    scope: None,
+                                }),
+                                None => msgs_out.push(Event {
+                                    name: "thread_name".to_owned(),
+                                    cat: "Task".to_owned(),
+                                    ty: EventType::Metadata,
+                                    pid: 1,
+                                    tid,
+                                    ts: 0.0,
+                                    args: Some(
+                                        serde_json::json!({ "name": format!("Core {} tasks", core) }),
+                                    ),
+                                    scope: None,
+                                }),
+                            }
+                            msgs_out.push(Event {
+                                name: format!("Task {}", task),
+                                cat: "Task".to_owned(),
+                                ty: EventType::SpanBegin,
+                                pid: 1,
+                                tid,
+                                ts,
+                                args: None,
+                                scope: None,
+                            });
+
+                            for msg_out in msgs_out {
+                                let _ = file
+                                    .write_all(serde_json::to_string(&msg_out).unwrap().as_bytes());
+                                let _ = file.write_all(",\n".as_bytes());
+                            }
+                        }
+
+                        // Spans, which were open when the firmware panicked, end at the panic
+                        TimestampedTracepoint::Panic {
+                            timestamp,
+                            core,
+                            location,
+                            ..
+                        } => {
+                            let ts = self.timestamp.to_micros(timestamp);
+                            last_ts = ts;
+
+                            let mut msgs_out = vec![Event {
+                                name: match location {
+                                    Some(ref location) => format!("Panic at {}", location),
+                                    None => "Panic".to_owned(),
+                                },
+                                cat: "Panic".to_owned(),
+                                ty: EventType::Instant,
+                                pid: 1,
+                                tid: core as u32 + 1,
+                                ts,
+                                args: location.map(|l| serde_json::json!({ "location": l })),
+                                scope: Some("g".to_owned()),
+                            }];
+                            for (tid, spans) in open_spans.drain() {
+                                for name in spans.into_iter().rev() {
+                                    msgs_out.push(Event {
+                                        name,
+                                        cat: "Panic".to_owned(),
+                                        ty: EventType::SpanEnd,
+                                        pid: 1,
+                                        tid,
+                                        ts,
+                                        args: None,
+                                        scope: None,
+                                    });
+                                }
+                            }
+                            active_isrs.clear();
+                            for (core, task) in running_tasks.drain() {
+                                msgs_out.push(Event {
+                                    name: format!("Task {}", task),
+                                    cat: "Task".to_owned(),
+                                    ty: EventType::SpanEnd,
+                                    pid: 1,
+                                    tid: TASK_TRACK_OFFSET + core as u32,
+                                    ts,
+                                    args: None,
+                                    scope: None,
+                                });
+                            }
+
+                            for msg_out in msgs_out {
+                                let _ = file
+                                    .write_all(serde_json::to_string(&msg_out).unwrap().as_bytes());
+                                let _ = file.write_all(",\n".as_bytes());
+                            }
+                        }
+
+                        // Draw a marker across the whole trace, so the gap is visible
+                        TimestampedTracepoint::Lost { count } => {
+                            warn!("Lost {} trace points", count);
+
+                            let msg_out = Event {
+                                name: format!("Lost {} events", count),
+                                cat: "Lost".to_owned(),
+                                ty: EventType::Instant,
+                                pid: 1,
+                                tid: 1,
+                                ts: last_ts,
+                                args: Some(serde_json::json!({ "count": count })),
+                                scope: Some("g".to_owned()),
+                            };
+                            let _ =
+                                file.write_all(serde_json::to_string(&msg_out).unwrap().as_bytes());
+                            let _ = file.write_all(",\n".as_bytes());
                         }
 
                         // Properly close the JSON array
@@ -256,3 +625,242 @@ impl Store {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use tokio::sync::broadcast::channel;
+    use utrace_core::trace_point::{
+        TracePointDataWithLocation, TracePointId, TracePointInfo, TracePointKind,
+    };
+
+    // Entry and exit of a span share the hash id
+    fn trace_point(kind: TracePointKind, name: &str, hash_id: u64) -> TracePointDataWithLocation {
+        TracePointDataWithLocation {
+            info: TracePointInfo {
+                kind,
+                name: Some(name.to_owned()),
+                comment: None,
+                skip: None,
+                id: hash_id,
+                format: None,
+                min_interval_us: None,
+            },
+            path: None,
+            file_name: None,
+            line: None,
+        }
+    }
+
+    fn point(
+        tracepoint: &TracePointDataWithLocation,
+        timestamp: u64,
+        core: u8,
+        instance: Option<u32>,
+    ) -> TimestampedTracepoint<'_> {
+        TimestampedTracepoint::Point {
+            timestamp,
+            core,
+            tracepoint,
+            value: None,
+            instance,
+        }
+    }
+
+    // Stores the events and returns the written trace events
+    async fn store(
+        trace_points: &HashMap<TracePointId, TracePointDataWithLocation>,
+        events: Vec<TimestampedTracepoint<'_>>,
+    ) -> Vec<serde_json::Value> {
+        static NEXT_DIR: AtomicU32 = AtomicU32::new(0);
+        let dir = std::env::temp_dir().join(format!(
+            "utrace-chrometracing-{}-{}",
+            std::process::id(),
+            NEXT_DIR.fetch_add(1, Ordering::Relaxed)
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let (tx, rx) = channel(events.len() + 1);
+        for event in events {
+            tx.send(event).unwrap();
+        }
+        drop(tx);
+
+        let store = Store::new(trace_points, TimestampInfo { freq_hz: 1_000_000 });
+        store.store(dir.join("trace").to_str().unwrap(), rx).await;
+
+        let file = std::fs::read_dir(&dir).unwrap().next().unwrap().unwrap();
+        let json = std::fs::read_to_string(file.path()).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        json.lines()
+            .filter(|line| line.starts_with('{'))
+            .map(|line| serde_json::from_str(line.trim_end_matches(',')).unwrap())
+            .collect()
+    }
+
+    // Phase, name and track of the events of the given phases
+    fn phases<'a>(events: &'a [serde_json::Value], ph: &str) -> Vec<(&'a str, &'a str, u64)> {
+        events
+            .iter()
+            .filter(|e| ph.contains(e["ph"].as_str().unwrap()))
+            .map(|e| {
+                let name = match e["ph"].as_str() {
+                    Some("M") => e["args"]["name"].as_str().unwrap(),
+                    _ => e["name"].as_str().unwrap(),
+                };
+                (e["ph"].as_str().unwrap(), name, e["tid"].as_u64().unwrap())
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn async_instances() {
+        let trace_points = HashMap::from([
+            (1, trace_point(TracePointKind::AsyncEnter, "job", 1)),
+            (2, trace_point(TracePointKind::AsyncExit, "job", 1)),
+            (3, trace_point(TracePointKind::AsyncPollEnter, "job", 2)),
+            (4, trace_point(TracePointKind::AsyncPollExit, "job", 2)),
+        ]);
+        let tp = |id| &trace_points[&id];
+
+        let events = store(
+            &trace_points,
+            vec![
+                point(tp(1), 10, 0, Some(1)),
+                point(tp(1), 20, 0, Some(2)),
+                point(tp(3), 30, 0, Some(1)),
+                point(tp(4), 40, 0, Some(1)),
+                point(tp(2), 50, 0, Some(1)),
+                point(tp(2), 60, 0, Some(2)),
+            ],
+        )
+        .await;
+
+        // Polls are nested into the lifetime of their instance
+        let tracks: Vec<_> = events
+            .iter()
+            .filter(|e| matches!(e["ph"].as_str(), Some("b" | "e")))
+            .map(|e| {
+                (
+                    e["ph"].as_str().unwrap(),
+                    e["name"].as_str().unwrap(),
+                    e["id"].as_u64().unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            tracks,
+            [
+                ("b", "job", 1),
+                ("b", "job", 2),
+                ("b", "poll", 1),
+                ("e", "poll", 1),
+                ("e", "job", 1),
+                ("e", "job", 2),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn interrupt_track() {
+        let trace_points = HashMap::from([
+            (1, trace_point(TracePointKind::SyncEnter, "work", 1)),
+            (2, trace_point(TracePointKind::SyncExit, "work", 1)),
+            (3, trace_point(TracePointKind::IsrEnter, "uart", 2)),
+            (4, trace_point(TracePointKind::IsrExit, "uart", 2)),
+            (5, trace_point(TracePointKind::SyncEnter, "rx", 3)),
+            (6, trace_point(TracePointKind::SyncExit, "rx", 3)),
+        ]);
+        let tp = |id| &trace_points[&id];
+
+        let mut isr_enter = point(tp(3), 20, 1, None);
+        if let TimestampedTracepoint::Point { ref mut value, .. } = isr_enter {
+            *value = Some(Value::U8(2));
+        }
+        let events = store(
+            &trace_points,
+            vec![
+                point(tp(1), 10, 1, None),
+                isr_enter,
+                point(tp(5), 30, 1, None),
+                point(tp(6), 40, 1, None),
+                point(tp(4), 50, 1, None),
+                point(tp(2), 60, 1, None),
+            ],
+        )
+        .await;
+
+        // The interrupt and the spans it starts do not break the preempted span
+        assert_eq!(
+            phases(&events, "MBE"),
+            [
+                ("M", "Core 1", 2),
+                ("B", "work", 2),
+                ("M", "Core 1 interrupts", 2001),
+                ("B", "uart", 2001),
+                ("B", "rx", 2001),
+                ("E", "rx", 2001),
+                ("E", "uart", 2001),
+                ("E", "work", 2),
+            ]
+        );
+        let priority = events
+            .iter()
+            .find(|e| e["name"] == "uart" && e["ph"] == "B")
+            .map(|e| e["args"]["priority"].clone());
+        assert_eq!(priority, Some(2.into()));
+    }
+
+    #[tokio::test]
+    async fn tasks_and_panic() {
+        let trace_points = HashMap::from([
+            (1, trace_point(TracePointKind::SyncEnter, "work", 1)),
+            (2, trace_point(TracePointKind::SyncExit, "work", 1)),
+            (3, trace_point(TracePointKind::Panic, "panic", 2)),
+        ]);
+        let tp = |id| &trace_points[&id];
+
+        let events = store(
+            &trace_points,
+            vec![
+                TimestampedTracepoint::TaskSwitch {
+                    timestamp: 10,
+                    core: 1,
+                    task: 3,
+                },
+                TimestampedTracepoint::TaskSwitch {
+                    timestamp: 20,
+                    core: 1,
+                    task: 4,
+                },
+                point(tp(1), 30, 0, None),
+                TimestampedTracepoint::Panic {
+                    timestamp: 40,
+                    core: 0,
+                    tracepoint: tp(3),
+                    location: Some("src/main.rs:42:5".to_owned()),
+                },
+            ],
+        )
+        .await;
+
+        // Spans and tasks, which were running at the panic, end at it
+        assert_eq!(
+            phases(&events, "MBEi"),
+            [
+                ("M", "Core 1 tasks", 1001),
+                ("B", "Task 3", 1001),
+                ("E", "Task 3", 1001),
+                ("B", "Task 4", 1001),
+                ("M", "Core 0", 1),
+                ("B", "work", 1),
+                ("i", "Panic at src/main.rs:42:5", 1),
+                ("E", "work", 1),
+                ("E", "Task 4", 1001),
+            ]
+        );
+        assert!(events.iter().all(|e| e["ts"].as_f64() <= Some(40.0)));
+    }
+}
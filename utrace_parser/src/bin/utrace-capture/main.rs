@@ -1,14 +1,17 @@
 use anyhow::{bail, Result};
 use chrometracing::Store;
 use clap::Parser;
-use std::collections::HashMap;
 use std::path::PathBuf;
-use tokio::io::AsyncReadExt;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
 use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
 use tokio::sync::broadcast::{channel, Receiver, Sender};
-use tracing::error;
-use utrace_core::trace_point::{TracePointDataWithLocation, TracePointId};
-use utrace_parser::stream_parser::TimestampedTracepoint;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tracing::{error, warn};
+use utrace_core::command::{encode_command, Command};
+use utrace_core::trace_point::TimestampInfo;
+use utrace_parser::elf_parser::Metadata;
+use utrace_parser::memory_dump::{flight_recorder_stream, MemoryDump};
+use utrace_parser::stream_parser::{StreamParser, TimestampedTracepoint};
 
 const EVENT_QUEUE_LENGTH: usize = 1024;
 
@@ -26,27 +29,118 @@ struct Args {
     #[arg(short = 'p', long = "stdin")]
     stdin: bool,
 
+    /// Memory dump of the target, with flight recorder buffer
+    #[arg(short = 'm', long = "memory", value_name = "DUMP")]
+    memory: Option<PathBuf>,
+
+    /// Address of the raw memory dump; without it, the dump is read as an elf core file
+    #[arg(short = 'b', long = "base", value_name = "ADDR", value_parser = parse_address)]
+    base: Option<u64>,
+
     #[arg(short = 'j', long = "out-ct", value_name = "LOG_PREFIX")]
     chrometracing: Option<String>,
 
     #[arg(short = 'c', long)]
     stdout: bool,
+
+    /// Command for the target, sent after connecting, e.g. "disable NAME"
+    #[arg(short = 'x', long = "command", value_name = "COMMAND")]
+    commands: Vec<String>,
+
+    /// Read commands for the target from stdin
+    #[arg(short = 'i', long)]
+    interactive: bool,
+}
+
+fn parse_address(s: &str) -> Result<u64, String> {
+    let s = s.replace('_', "");
+    match s.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => s.parse(),
+    }
+    .map_err(|e| e.to_string())
+}
+
+fn encode_commands(commands: &[Command]) -> Vec<u8> {
+    let mut data = Vec::new();
+    for command in commands {
+        encode_command(*command, |buf| data.extend_from_slice(buf));
+    }
+
+    data
+}
+
+// Forwards trace points, parsed from the received data, and warns about corrupted data
+fn parse_chunk<'a>(
+    sd: &mut StreamParser<'a>,
+    data: &[u8],
+    chan: &Sender<TimestampedTracepoint<'a>>,
+) {
+    let invalid_packets = sd.stats().invalid_packets;
+
+    for p in sd.push_and_parse(data) {
+        chan.send(p).expect("Event queue overflow");
+    }
+
+    let stats = sd.stats();
+    if stats.invalid_packets != invalid_packets {
+        warn!(
+            "Trace stream is corrupted, check the source. Invalid packets: {}, discarded bytes: {}",
+            stats.invalid_packets, stats.discarded_bytes
+        );
+    }
+}
+
+// Receives the trace stream from the socket and sends the commands to the target
+async fn socket_reader<'a>(
+    mut socket: TcpStream,
+    chan: &Sender<TimestampedTracepoint<'a>>,
+    metadata: &'a Metadata,
+    commands: &[u8],
+    interactive: &mut Option<UnboundedReceiver<Vec<u8>>>,
+) {
+    let (mut rx, mut tx) = socket.split();
+    let mut sd = StreamParser::new(metadata);
+    let mut buf = [0u8; 16536];
+
+    if tx.write_all(commands).await.is_err() {
+        error!("Unable to send commands to the target.");
+    }
+
+    loop {
+        tokio::select! {
+            read = rx.read(&mut buf) => match read {
+                Ok(read) if read > 0 => parse_chunk(&mut sd, &buf[..read], chan),
+                _ => break,
+            },
+            data = next_command(interactive) => match data {
+                Some(data) => {
+                    if tx.write_all(&data).await.is_err() {
+                        error!("Unable to send commands to the target.");
+                    }
+                }
+                None => *interactive = None,
+            },
+        }
+    }
+}
+
+async fn next_command(interactive: &mut Option<UnboundedReceiver<Vec<u8>>>) -> Option<Vec<u8>> {
+    match interactive {
+        Some(commands) => commands.recv().await,
+        None => std::future::pending().await,
+    }
 }
 
 async fn net_reader<'a>(
     addr: impl ToSocketAddrs,
     chan: Sender<TimestampedTracepoint<'a>>,
-    id_mapping: &'a HashMap<TracePointId, TracePointDataWithLocation>,
+    metadata: &'a Metadata,
+    commands: Vec<u8>,
+    mut interactive: Option<UnboundedReceiver<Vec<u8>>>,
 ) {
-    if let Ok(mut socket) = TcpStream::connect(addr).await {
-        let mut sd = utrace_parser::stream_parser::StreamParser::new(id_mapping);
-        let mut buf = [0u8; 16536];
-
-        while let Ok(read) = socket.read(&mut buf).await {
-            for p in sd.push_and_parse(&buf[..read]) {
-                chan.send(p).expect("Event queue overflow");
-            }
-        }
+    if let Ok(socket) = TcpStream::connect(addr).await {
+        socket_reader(socket, &chan, metadata, &commands, &mut interactive).await;
     } else {
         error!("Unable to connect to requested address.");
     }
@@ -55,7 +149,9 @@ async fn net_reader<'a>(
 async fn net_server_reader<'a>(
     addr: impl ToSocketAddrs,
     chan: Sender<TimestampedTracepoint<'a>>,
-    id_mapping: &'a HashMap<TracePointId, TracePointDataWithLocation>,
+    metadata: &'a Metadata,
+    commands: Vec<u8>,
+    mut interactive: Option<UnboundedReceiver<Vec<u8>>>,
 ) {
     let l = TcpListener::bind(addr).await;
 
@@ -65,37 +161,64 @@ async fn net_server_reader<'a>(
     }
     let l = l.unwrap();
 
-    while let Ok((mut socket, _)) = l.accept().await {
-        let mut sd = utrace_parser::stream_parser::StreamParser::new(id_mapping);
-        let mut buf = [0u8; 16536];
+    while let Ok((socket, _)) = l.accept().await {
+        socket_reader(socket, &chan, metadata, &commands, &mut interactive).await;
+    }
+    error!("Network error.");
+}
 
-        while let Ok(read) = socket.read(&mut buf).await {
-            for p in sd.push_and_parse(&buf[..read]) {
-                chan.send(p).expect("Event queue overflow");
+// Reads commands from stdin, one per line, and encodes them for the target
+async fn command_reader(chan: UnboundedSender<Vec<u8>>, metadata: &Metadata) {
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+
+    while let Ok(Some(line)) = lines.next_line().await {
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        match utrace_parser::command::parse(&line, metadata) {
+            Ok(commands) => {
+                if chan.send(encode_commands(&commands)).is_err() {
+                    break;
+                }
             }
+            Err(e) => error!("{:#}", e),
         }
     }
-    error!("Network error.");
 }
 
-async fn stdin_reader<'a>(
-    chan: Sender<TimestampedTracepoint<'a>>,
-    id_mapping: &'a HashMap<TracePointId, TracePointDataWithLocation>,
-) {
-    let mut sd = utrace_parser::stream_parser::StreamParser::new(id_mapping);
+async fn stdin_reader<'a>(chan: Sender<TimestampedTracepoint<'a>>, metadata: &'a Metadata) {
+    let mut sd = StreamParser::new(metadata);
     let mut buf = [0u8; 16536];
     let mut stdin = tokio::io::stdin();
 
     while let Ok(read) = stdin.read(&mut buf).await {
-        for p in sd.push_and_parse(&buf[..read]) {
-            chan.send(p).expect("Event queue overflow");
+        parse_chunk(&mut sd, &buf[..read], &chan);
+    }
+}
+
+// Sends the trace, extracted from a memory dump, and waits until it is consumed
+async fn memory_reader<'a>(
+    chan: Sender<TimestampedTracepoint<'a>>,
+    metadata: &'a Metadata,
+    stream: Vec<u8>,
+) {
+    let mut sd = StreamParser::new(metadata);
+
+    for chunk in stream.chunks(256) {
+        parse_chunk(&mut sd, chunk, &chan);
+        while !chan.is_empty() {
+            tokio::task::yield_now().await;
         }
     }
 }
 
-async fn tp_consumer<'a>(mut chan: Receiver<TimestampedTracepoint<'a>>) {
+async fn tp_consumer<'a>(mut chan: Receiver<TimestampedTracepoint<'a>>, timestamp: TimestampInfo) {
     while let Ok(p) = chan.recv().await {
-        println!("{:?}", p);
+        match p.timestamp() {
+            Some(ts) => println!("{:.3} us: {:?}", timestamp.to_micros(ts), p),
+            None => println!("{:?}", p),
+        }
     }
 }
 
@@ -105,38 +228,79 @@ async fn main() -> Result<()> {
 
     tracing_subscriber::fmt().init();
 
-    let sources =
-        args.tcp.is_some() as usize + args.tcp_server.is_some() as usize + args.stdin as usize;
+    let sources = args.tcp.is_some() as usize
+        + args.tcp_server.is_some() as usize
+        + args.stdin as usize
+        + args.memory.is_some() as usize;
 
     if sources > 1 {
-        bail!("--tcp and --tcp-server and --stdin are mutually exclusive");
+        bail!("--tcp and --tcp-server and --stdin and --memory are mutually exclusive");
     }
 
     if sources < 1 {
         bail!("Stream source is not specified");
     }
 
-    let tp_data: HashMap<u8, TracePointDataWithLocation> =
-        utrace_parser::elf_parser::parse(args.elf)?;
+    if (args.stdin || args.memory.is_some()) && (args.interactive || !args.commands.is_empty()) {
+        bail!("Commands can be sent to the target only with --tcp or --tcp-server");
+    }
 
-    let store_trace = Store::new(&tp_data);
+    let metadata = utrace_parser::elf_parser::parse(&args.elf)?;
+
+    let memory_stream = match args.memory {
+        Some(ref path) => {
+            let dump = match args.base {
+                Some(base) => MemoryDump::from_raw(std::fs::read(path)?, base),
+                None => MemoryDump::from_core_file(path)?,
+            };
+            Some(flight_recorder_stream(&args.elf, &dump)?)
+        }
+        None => None,
+    };
+
+    let mut commands = Vec::new();
+    for line in &args.commands {
+        commands.extend(utrace_parser::command::parse(line, &metadata)?);
+    }
+    let commands = encode_commands(&commands);
+
+    let store_trace = Store::new(&metadata.trace_points, metadata.timestamp);
 
     async_scoped::TokioScope::scope_and_block(|s| {
         let (tptx, tprx) = channel(EVENT_QUEUE_LENGTH);
-        if let Some(addr) = args.tcp {
-            s.spawn(net_reader(addr, tptx, &tp_data));
-        } else if let Some(addr) = args.tcp_server {
-            s.spawn(net_server_reader(addr, tptx, &tp_data));
-        } else if args.stdin {
-            s.spawn(stdin_reader(tptx, &tp_data));
-        }
 
+        let interactive = if args.interactive {
+            let (cmdtx, cmdrx) = unbounded_channel();
+            s.spawn(command_reader(cmdtx, &metadata));
+            Some(cmdrx)
+        } else {
+            None
+        };
+
+        // Consumers are subscribed first, so that they receive the whole trace
         if args.stdout {
-            s.spawn(tp_consumer(tprx.resubscribe()));
+            s.spawn(tp_consumer(tprx.resubscribe(), metadata.timestamp));
         }
         if let Some(ref ct_file) = args.chrometracing {
             s.spawn(store_trace.store(ct_file, tprx.resubscribe()));
         }
+
+        if let Some(addr) = args.tcp {
+            s.spawn(net_reader(addr, tptx, &metadata, commands, interactive));
+        } else if let Some(addr) = args.tcp_server {
+            s.spawn(net_server_reader(
+                addr,
+                tptx,
+                &metadata,
+                commands,
+                interactive,
+            ));
+        } else if args.stdin {
+            s.spawn(stdin_reader(tptx, &metadata));
+        } else if let Some(stream) = memory_stream {
+            s.spawn(memory_reader(tptx, &metadata, stream));
+        }
+
         drop(tprx);
     });
 
@@ -0,0 +1,174 @@
+use anyhow::{bail, Context, Result};
+use tracing::warn;
+use utrace_core::command::Command;
+use utrace_core::trace_point::{TracePointDataWithLocation, TracePointId};
+
+use crate::elf_parser::Metadata;
+
+// Default of UTRACE_ENABLE_BITMAP_SIZE. Trace points with larger ids can not be
+// disabled, unless the firmware was built with a larger bitmap
+const DEFAULT_ENABLE_BITMAP_SIZE: TracePointId = 256;
+
+/// Parses a command for the target, resolving trace point names through the elf file
+/// metadata. Supported commands:
+/// - `start`, `stop` --- enable or disable tracing
+/// - `enable NAME`, `disable NAME` --- enable or disable trace points with the given
+///   name or comment
+/// - `skip NAME N` --- report the trace points only each Nth time, 0 restores the limit,
+///   set at compile time. Only the trace points with `skip` attribute can be changed
+/// - `init` --- re-initialize tracing, as if `utrace::init` was called
+/// - `resync` --- send absolute timestamp with the next trace point
+pub fn parse(line: &str, metadata: &Metadata) -> Result<Vec<Command>> {
+    let words: Vec<_> = line.split_whitespace().collect();
+
+    let commands = match words[..] {
+        ["start"] => vec![Command::SetEnabled(true)],
+        ["stop"] => vec![Command::SetEnabled(false)],
+        ["enable", name] | ["disable", name] => {
+            let enabled = words[0] == "enable";
+            let points = find(name, metadata)?;
+
+            // Such trace points are always enabled, if the bitmap does not cover them
+            let uncovered = points
+                .iter()
+                .find(|(id, _)| *id >= DEFAULT_ENABLE_BITMAP_SIZE);
+            if let (false, Some((id, _))) = (enabled, uncovered) {
+                warn!(
+                    "Trace point {name:?} has id {id}, it can be disabled only if UTRACE_ENABLE_BITMAP_SIZE of the firmware is above it ({DEFAULT_ENABLE_BITMAP_SIZE} by default)"
+                );
+            }
+
+            points
+                .into_iter()
+                .map(|(id, _)| Command::SetTracePointEnabled { id, enabled })
+                .collect()
+        }
+        ["skip", name, limit] => {
+            let limit = limit
+                .parse()
+                .with_context(|| format!("Invalid skip limit {limit:?}"))?;
+
+            // The limit is looked up by the entry point of a span, or by its exit
+            // point if the entry is not traced
+            let points: Vec<_> = find(name, metadata)?
+                .into_iter()
+                .filter(|(_, tp)| tp.info.skip.is_some())
+                .collect();
+            if points.is_empty() {
+                bail!("Trace point {name:?} has no skip attribute");
+            }
+            let has_entry = points.iter().any(|(_, tp)| tp.info.kind.is_enter());
+
+            points
+                .into_iter()
+                .filter(|(_, tp)| !has_entry || tp.info.kind.is_enter())
+                .map(|(id, _)| Command::SetSkip { id, limit })
+                .collect()
+        }
+        ["init"] => vec![Command::Init],
+        ["resync"] => vec![Command::Resync],
+        _ => bail!("Unknown command {line:?}"),
+    };
+
+    Ok(commands)
+}
+
+fn find<'a>(
+    name: &str,
+    metadata: &'a Metadata,
+) -> Result<Vec<(TracePointId, &'a TracePointDataWithLocation)>> {
+    let mut points: Vec<_> = metadata
+        .trace_points
+        .iter()
+        .filter(|(_, tp)| {
+            tp.info.name.as_deref() == Some(name) || tp.info.comment.as_deref() == Some(name)
+        })
+        .map(|(id, tp)| (*id, tp))
+        .collect();
+
+    if points.is_empty() {
+        bail!("Trace point {name:?} is not found in the elf file");
+    }
+    points.sort_by_key(|(id, _)| *id);
+
+    Ok(points)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::HashMap;
+    use utrace_core::trace_point::{TracePointInfo, TracePointKind};
+
+    fn trace_point(
+        kind: TracePointKind,
+        name: &str,
+        skip: Option<u32>,
+    ) -> TracePointDataWithLocation {
+        TracePointDataWithLocation {
+            info: TracePointInfo {
+                kind,
+                name: Some(name.to_owned()),
+                comment: None,
+                skip,
+                id: 0,
+                format: None,
+                min_interval_us: None,
+            },
+            path: None,
+            file_name: None,
+            line: None,
+        }
+    }
+
+    fn metadata() -> Metadata {
+        let mut trace_points = HashMap::new();
+        trace_points.insert(1, trace_point(TracePointKind::SyncEnter, "foo", None));
+        trace_points.insert(2, trace_point(TracePointKind::SyncExit, "foo", None));
+        trace_points.insert(3, trace_point(TracePointKind::SyncEnter, "bar", Some(4)));
+        trace_points.insert(4, trace_point(TracePointKind::SyncExit, "bar", Some(4)));
+        trace_points.insert(5, trace_point(TracePointKind::GenericExit, "baz", Some(2)));
+        Metadata {
+            trace_points,
+            build_id: 0,
+            timestamp: Default::default(),
+            rodata: Default::default(),
+        }
+    }
+
+    #[test]
+    fn commands() {
+        let metadata = metadata();
+
+        assert_eq!(
+            parse("disable foo", &metadata).unwrap(),
+            vec![
+                Command::SetTracePointEnabled {
+                    id: 1,
+                    enabled: false
+                },
+                Command::SetTracePointEnabled {
+                    id: 2,
+                    enabled: false
+                },
+            ]
+        );
+        assert_eq!(
+            parse(" skip  bar 10 ", &metadata).unwrap(),
+            vec![Command::SetSkip { id: 3, limit: 10 }]
+        );
+        assert_eq!(
+            parse("skip baz 0", &metadata).unwrap(),
+            vec![Command::SetSkip { id: 5, limit: 0 }]
+        );
+        assert_eq!(
+            parse("stop", &metadata).unwrap(),
+            vec![Command::SetEnabled(false)]
+        );
+
+        assert!(parse("enable qux", &metadata).is_err());
+        assert!(parse("skip foo 2", &metadata).is_err());
+        assert!(parse("skip bar x", &metadata).is_err());
+        assert!(parse("restart", &metadata).is_err());
+    }
+}
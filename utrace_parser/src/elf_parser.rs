@@ -1,11 +1,26 @@
 use anyhow::{bail, Context, Result};
 use gimli::DwarfSections;
-use object::{Object, ObjectSection, ObjectSymbol};
+use object::{Object, ObjectSection, ObjectSymbol, SectionKind};
 use std::borrow;
 use std::{collections::HashMap, io::Read, path::Path};
-use utrace_core::trace_point::{TracePointDataWithLocation, TracePointId};
+use utrace_core::build_id;
+use utrace_core::trace_point::{TimestampInfo, TracePointDataWithLocation, TracePointId};
+
+use crate::memory_dump::MemoryDump;
+
+/// Trace metadata, extracted from the elf file
+#[derive(Debug)]
+pub struct Metadata {
+    pub trace_points: HashMap<TracePointId, TracePointDataWithLocation>,
+    /// Build ID, which the firmware reports on `utrace::init`
+    pub build_id: u32,
+    /// Time base of the trace point timestamps
+    pub timestamp: TimestampInfo,
+    /// Read-only data of the firmware, used to resolve file names of panic locations
+    pub rodata: MemoryDump,
+}
 
-pub fn parse<T>(elf_file: T) -> Result<HashMap<TracePointId, TracePointDataWithLocation>>
+pub fn parse<T>(elf_file: T) -> Result<Metadata>
 where
     T: AsRef<Path> + std::fmt::Debug,
 {
@@ -44,6 +59,40 @@ where
         }
     }
 
+    let mut timestamp = None;
+    if let Some(section) = object.section_by_name(utrace_core::TIMESTAMP_INFO_SECTION_NAME) {
+        for symbol in object.symbols() {
+            if symbol.section_index() == Some(section.index()) {
+                let info = TimestampInfo::from_mangled_string(
+                    symbol.name().context("Invalid timestamp symbol metadata")?,
+                )?;
+                if timestamp.replace(info).is_some() {
+                    bail!("Provided elf file contains several timestamp functions");
+                }
+            }
+        }
+    }
+
+    let build_id = trace_point_list.iter().fold(0, |acc, (tp, idx)| {
+        build_id::combine(
+            acc,
+            build_id::trace_point_hash(build_id::metadata_hash(tp.as_bytes()), *idx),
+        )
+    });
+
+    let mut rodata = Vec::new();
+    for section in object.sections() {
+        if matches!(
+            section.kind(),
+            SectionKind::ReadOnlyData | SectionKind::ReadOnlyString
+        ) {
+            let data = section
+                .data()
+                .with_context(|| format!("Unable to read section {:?}", section.name()))?;
+            rodata.push((section.address(), data.to_vec()));
+        }
+    }
+
     let endian = if object.is_little_endian() {
         gimli::RunTimeEndian::Little
     } else {
@@ -166,5 +215,10 @@ where
         });
     }
 
-    Ok(ret)
+    Ok(Metadata {
+        trace_points: ret,
+        build_id,
+        timestamp: timestamp.unwrap_or_default(),
+        rodata: MemoryDump::from_regions(rodata),
+    })
 }
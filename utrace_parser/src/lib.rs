@@ -1,2 +1,4 @@
+pub mod command;
 pub mod elf_parser;
+pub mod memory_dump;
 pub mod stream_parser;
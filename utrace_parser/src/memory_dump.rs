@@ -0,0 +1,177 @@
+use anyhow::{bail, Context, Result};
+use object::{Object, ObjectSegment, ObjectSymbol};
+use std::path::Path;
+use utrace_core::encoding::{
+    encode_core, encode_reset, encode_timestamp, SessionHeader, SESSION_FLAG_BUFFERED,
+    SESSION_FLAG_FLIGHT_RECORDER,
+};
+
+// Symbols, exported by utrace with `flight_recorder` feature
+const BUFFER_SYMBOL: &str = "__utrace_buffer";
+const WRITE_POS_SYMBOL: &str = "__utrace_buffer_write_pos";
+const READ_POS_SYMBOL: &str = "__utrace_buffer_read_pos";
+const HEAD_CORE_SYMBOL: &str = "__utrace_buffer_head_core";
+const HEAD_TIMESTAMPS_SYMBOL: &str = "__utrace_buffer_head_timestamps";
+
+/// Memory of a target, saved by a debugger
+#[derive(Debug, Default)]
+pub struct MemoryDump {
+    regions: Vec<(u64, Vec<u8>)>,
+}
+
+impl MemoryDump {
+    /// Raw memory dump, which starts at the given address
+    pub fn from_raw(data: Vec<u8>, base: u64) -> Self {
+        MemoryDump {
+            regions: vec![(base, data)],
+        }
+    }
+
+    /// Memory, which consists of several regions with their start addresses
+    pub fn from_regions(regions: Vec<(u64, Vec<u8>)>) -> Self {
+        MemoryDump { regions }
+    }
+
+    /// Elf core file, e.g. saved by gdb `gcore` command
+    pub fn from_core_file<T>(core_file: T) -> Result<Self>
+    where
+        T: AsRef<Path> + std::fmt::Debug,
+    {
+        let file_data = std::fs::read(core_file.as_ref())
+            .with_context(|| format!("Unable to read core file {:?}", core_file))?;
+        let object =
+            object::File::parse(file_data.as_slice()).context("Unable to parse core file")?;
+
+        let mut regions = Vec::new();
+        for segment in object.segments() {
+            let data = segment.data().context("Invalid segment in core file")?;
+            regions.push((segment.address(), data.to_vec()));
+        }
+
+        Ok(MemoryDump { regions })
+    }
+
+    pub fn read(&self, address: u64, len: usize) -> Result<&[u8]> {
+        for (base, data) in &self.regions {
+            if let Some(offset) = address.checked_sub(*base) {
+                if offset.saturating_add(len as u64) <= data.len() as u64 {
+                    return Ok(&data[offset as usize..offset as usize + len]);
+                }
+            }
+        }
+
+        bail!(
+            "Memory dump does not contain {} bytes at {:#x}",
+            len,
+            address
+        )
+    }
+}
+
+/// Reads the flight recorder buffer of the firmware from the memory dump, and returns
+/// the trace stream, which can be passed to [StreamParser](crate::stream_parser::StreamParser).
+/// The firmware should be built with `flight_recorder` feature of utrace.
+pub fn flight_recorder_stream<T>(elf_file: T, dump: &MemoryDump) -> Result<Vec<u8>>
+where
+    T: AsRef<Path> + std::fmt::Debug,
+{
+    let file_data = std::fs::read(elf_file.as_ref())
+        .with_context(|| format!("Unable to read elf file {:?}", elf_file))?;
+    let object = object::File::parse(file_data.as_slice()).context("Unable to parse elf file")?;
+
+    let read_symbol = |name: &str| -> Result<&[u8]> {
+        let symbol = object.symbol_by_name(name).with_context(|| {
+            format!("Flight recorder symbol {name} is not found in the elf file")
+        })?;
+        dump.read(symbol.address(), symbol.size() as usize)
+    };
+    let read_uint = |bytes: &[u8]| {
+        let mut raw = [0; 8];
+        if object.is_little_endian() {
+            raw[..bytes.len()].copy_from_slice(bytes);
+            u64::from_le_bytes(raw)
+        } else {
+            raw[8 - bytes.len()..].copy_from_slice(bytes);
+            u64::from_be_bytes(raw)
+        }
+    };
+
+    let buffer = read_symbol(BUFFER_SYMBOL)?;
+    let write_pos = read_uint(read_symbol(WRITE_POS_SYMBOL)?);
+    let read_pos = read_uint(read_symbol(READ_POS_SYMBOL)?);
+    let head_core = read_uint(read_symbol(HEAD_CORE_SYMBOL)?) as u8;
+    let head_timestamps: Vec<_> = read_symbol(HEAD_TIMESTAMPS_SYMBOL)?
+        .chunks(8)
+        .map(read_uint)
+        .collect();
+
+    let pos_mask = if object.is_64() {
+        u64::MAX
+    } else {
+        u32::MAX as u64
+    };
+    let data = ring_data(buffer, read_pos, write_pos, pos_mask)?;
+
+    // Same as the firmware sends on utrace::dump()
+    let mut stream = Vec::new();
+    encode_reset(
+        SessionHeader {
+            flags: SESSION_FLAG_BUFFERED | SESSION_FLAG_FLIGHT_RECORDER,
+            ..Default::default()
+        },
+        |b| stream.extend_from_slice(b),
+    );
+    for (core, timestamp) in head_timestamps.into_iter().enumerate() {
+        encode_core(core as u8, |b| stream.extend_from_slice(b));
+        encode_timestamp(timestamp, |b| stream.extend_from_slice(b));
+    }
+    encode_core(head_core, |b| stream.extend_from_slice(b));
+    stream.extend_from_slice(&data);
+
+    Ok(stream)
+}
+
+// Positions are the total numbers of bytes written to and read from the buffer,
+// which wrap around at the width of usize of the target
+fn ring_data(buffer: &[u8], read_pos: u64, write_pos: u64, pos_mask: u64) -> Result<Vec<u8>> {
+    let len = write_pos.wrapping_sub(read_pos) & pos_mask;
+    if buffer.is_empty() || len > buffer.len() as u64 {
+        bail!("Flight recorder buffer is corrupted");
+    }
+
+    Ok((0..len)
+        .map(|i| buffer[(read_pos.wrapping_add(i) % buffer.len() as u64) as usize])
+        .collect())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn memory_regions() {
+        let dump = MemoryDump::from_raw((0..16).collect(), 0x2000_0000);
+
+        assert_eq!(dump.read(0x2000_0004, 3).unwrap(), &[4, 5, 6]);
+        assert_eq!(dump.read(0x2000_000c, 4).unwrap(), &[12, 13, 14, 15]);
+        assert!(dump.read(0x2000_000c, 5).is_err());
+        assert!(dump.read(0x1fff_ffff, 1).is_err());
+    }
+
+    #[test]
+    fn ring_wrap_around() {
+        let buffer = [4, 5, 6, 7, 0, 1, 2, 3];
+
+        assert_eq!(
+            ring_data(&buffer, 4, 12, u64::MAX).unwrap(),
+            (0..8).collect::<Vec<_>>()
+        );
+        assert_eq!(ring_data(&buffer, 5, 7, u64::MAX).unwrap(), vec![1, 2]);
+        // Positions of a 32-bit target wrapped around
+        assert_eq!(
+            ring_data(&buffer, 0xffff_fffe, 2, u32::MAX as u64).unwrap(),
+            vec![2, 3, 4, 5]
+        );
+        assert!(ring_data(&buffer, 0, 9, u64::MAX).is_err());
+    }
+}
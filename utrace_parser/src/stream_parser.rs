@@ -1,33 +1,181 @@
 use std::collections::HashMap;
 
 use tracing::error;
-use utrace_core::encoding::Decoder;
-use utrace_core::trace_point::{TracePointDataWithLocation, TracePointId};
+use utrace_core::encoding::{
+    Decoder, DecoderStats, Packet, SessionHeader, Value, PROTOCOL_VERSION, SEQUENCE_MODULO,
+    SESSION_FLAG_FLIGHT_RECORDER,
+};
+use utrace_core::trace_point::{split_format, TracePointDataWithLocation, TracePointKind};
+
+use crate::elf_parser::Metadata;
 
 #[derive(Debug, Clone)]
 pub enum TimestampedTracepoint<'a> {
     Point {
         timestamp: u64,
+        /// Core, which has emitted the trace point. Always 0, unless the target uses
+        /// `multicore` feature of utrace
+        core: u8,
+        tracepoint: &'a TracePointDataWithLocation,
+        /// Value of value and counter points, or the number of calls, suppressed by
+        /// `min_interval_us` before this one
+        value: Option<Value>,
+        /// Instance of the traced async fn, which has emitted the trace point. Each
+        /// future, returned by the fn, is a separate instance
+        instance: Option<u32>,
+    },
+    /// Trace points were lost. Reported if the target has dropped trace points because
+    /// its transport was full, or, if the target emits sequence packets (`sequence`
+    /// feature of utrace), when trace points were lost on the way from the target.
+    Lost {
+        count: u32,
+    },
+    /// Log message, emitted by `utrace::log!`
+    Log {
+        timestamp: u64,
+        core: u8,
+        tracepoint: &'a TracePointDataWithLocation,
+        message: String,
+    },
+    /// Marker with an arbitrary code, emitted by `utrace::marker`
+    Marker {
+        timestamp: u64,
+        core: u8,
+        code: u32,
+    },
+    /// The core has switched to another task, reported by `utrace::task_switch`
+    TaskSwitch {
+        timestamp: u64,
+        core: u8,
+        task: u32,
+    },
+    /// The firmware has panicked, reported by `utrace::panic_hook`
+    Panic {
+        timestamp: u64,
+        core: u8,
         tracepoint: &'a TracePointDataWithLocation,
+        /// Location of the panic as `file:line:column`, if it is known
+        location: Option<String>,
     },
     Reset,
 }
 
+impl TimestampedTracepoint<'_> {
+    /// Timestamp of the event in ticks of the timestamp function, if it has one
+    pub fn timestamp(&self) -> Option<u64> {
+        match self {
+            TimestampedTracepoint::Point { timestamp, .. }
+            | TimestampedTracepoint::Log { timestamp, .. }
+            | TimestampedTracepoint::Marker { timestamp, .. }
+            | TimestampedTracepoint::TaskSwitch { timestamp, .. }
+            | TimestampedTracepoint::Panic { timestamp, .. } => Some(*timestamp),
+            TimestampedTracepoint::Lost { .. } | TimestampedTracepoint::Reset => None,
+        }
+    }
+}
+
+// Limit of value packets, waiting for their trace point, so that garbage doesn't pile up
+const MAX_PENDING_VALUES: usize = 64;
+
+// Substitutes the last values into the format string placeholders
+fn format_message(format: &str, values: &[Value]) -> String {
+    let pieces = match split_format(format) {
+        Ok(pieces) => pieces,
+        Err(e) => return e,
+    };
+
+    let placeholders = pieces.len() - 1;
+    let values = &values[values.len().saturating_sub(placeholders)..];
+    let missing = placeholders - values.len();
+
+    let mut message = pieces[0].clone();
+    for (i, piece) in pieces[1..].iter().enumerate() {
+        match i.checked_sub(missing) {
+            Some(i) => message += &values[i].to_string(),
+            None => message += "?",
+        }
+        message += piece;
+    }
+
+    message
+}
+
+// Panic location is sent as the address and the length of the file name, the line
+// and the column. The file name is read from the elf file
+fn panic_location(values: &[Value], metadata: &Metadata) -> Option<String> {
+    let [Value::U32(file), Value::U32(len), Value::U32(line), Value::U32(column)] =
+        values[values.len().checked_sub(4)?..]
+    else {
+        return None;
+    };
+
+    let file = metadata.rodata.read(file as u64, len as usize).ok()?;
+    Some(format!(
+        "{}:{}:{}",
+        String::from_utf8_lossy(file),
+        line,
+        column
+    ))
+}
+
 pub struct StreamParser<'a> {
-    id_mapping: &'a HashMap<TracePointId, TracePointDataWithLocation>,
+    metadata: &'a Metadata,
     decoder_queue: Decoder,
-    timestamp: u64,
+    core: u8,
+    timestamps: HashMap<u8, u64>,
+    pending_values: Vec<Value>,
+    pending_instance: Option<u32>,
+    last_sequence: Option<u16>,
+    received_since_sequence: u32,
+    session: Option<SessionHeader>,
+    // Trace points are not interpreted until the next reset
+    skip_session: bool,
 }
 
 impl<'a> StreamParser<'a> {
-    pub fn new(id_mapping: &'a HashMap<TracePointId, TracePointDataWithLocation>) -> Self {
+    /// Trace points, received after the target has reported build ID, which does not
+    /// match the elf file, or an unsupported protocol version, are ignored until the
+    /// next reset.
+    pub fn new(metadata: &'a Metadata) -> Self {
         StreamParser {
-            id_mapping,
+            metadata,
             decoder_queue: Decoder::new(),
-            timestamp: 0,
+            core: 0,
+            timestamps: HashMap::new(),
+            pending_values: Vec::new(),
+            pending_instance: None,
+            last_sequence: None,
+            received_since_sequence: 0,
+            session: None,
+            skip_session: false,
         }
     }
 
+    /// Header of the current session, if it was received
+    pub fn session(&self) -> Option<SessionHeader> {
+        self.session
+    }
+
+    /// Statistics of stream corruption, e.g. caused by wrong baud rate or RTT channel
+    pub fn stats(&self) -> DecoderStats {
+        self.decoder_queue.stats()
+    }
+
+    // Values and the instance, received before the lost trace points, might belong
+    // to one of them, so they are not attached to the next trace point
+    fn lost(&mut self, count: u32) -> TimestampedTracepoint<'a> {
+        self.pending_values.clear();
+        self.pending_instance = None;
+        TimestampedTracepoint::Lost { count }
+    }
+
+    // Adds delta to the timestamp of the current core, returns the core and the new timestamp
+    fn advance(&mut self, delta_t: u32) -> (u8, u64) {
+        let timestamp = self.timestamps.entry(self.core).or_default();
+        *timestamp += delta_t as u64;
+        (self.core, *timestamp)
+    }
+
     pub fn push_and_parse<'b>(
         &'b mut self,
         data: &'b [u8],
@@ -50,28 +198,588 @@ impl<'a, 'b> Iterator for StreamParserIter<'a, 'b> {
     fn next(&mut self) -> Option<Self::Item> {
         while let Some((&b, rest)) = self.incoming.split_first() {
             self.incoming = rest;
-            if let Some(tp) = self.inner.decoder_queue.push_byte(b) {
-                if tp.id == 0 && tp.delta_t == 0 {
-                    // self.inner.timestamp = 0;
+            match self.inner.decoder_queue.push_byte(b) {
+                Some(Packet::Reset(header)) => {
+                    self.inner.core = 0;
+                    self.inner.pending_values.clear();
+                    self.inner.pending_instance = None;
+                    // Flight recorder dump starts in the middle of the trace
+                    self.inner.last_sequence = if header.flags & SESSION_FLAG_FLIGHT_RECORDER != 0 {
+                        None
+                    } else {
+                        Some(0)
+                    };
+                    self.inner.received_since_sequence = 0;
+                    self.inner.session = Some(header);
+                    self.inner.skip_session = false;
+
+                    if header.version <= PROTOCOL_VERSION {
+                        self.inner.decoder_queue.set_version(header.version);
+                    } else {
+                        error!(
+                            "Trace uses protocol version {}, the newest supported is {}. Ignoring trace points until reset.",
+                            header.version, PROTOCOL_VERSION
+                        );
+                        self.inner.skip_session = true;
+                    }
+
                     return Some(TimestampedTracepoint::Reset);
                 }
-
-                self.inner.timestamp += tp.delta_t as u64;
-                let data = self.inner.id_mapping.get(&tp.id);
-                if let Some(data) = data {
-                    return Some(TimestampedTracepoint::Point {
-                        timestamp: self.inner.timestamp,
-                        tracepoint: data,
-                    });
-                } else {
+                Some(Packet::BuildId(build_id)) if build_id != self.inner.metadata.build_id => {
                     error!(
-                        "Received trace packet with incorrect id={}. Ignoring.",
-                        tp.id
+                        "Trace was produced by a different firmware build (build id {:08x}, elf file has {:08x}). Ignoring trace points until reset.",
+                        build_id, self.inner.metadata.build_id
                     );
+                    self.inner.skip_session = true;
+                }
+                Some(Packet::BuildId(_)) => (),
+                Some(Packet::Timestamp(timestamp)) => {
+                    self.inner.timestamps.insert(self.inner.core, timestamp);
+                }
+                Some(Packet::Core(core)) => {
+                    self.inner.core = core;
+                }
+                Some(Packet::Overflow(count)) => {
+                    return Some(self.inner.lost(count));
+                }
+                Some(Packet::Sequence(seq)) => {
+                    let received = core::mem::take(&mut self.inner.received_since_sequence);
+                    let last_sequence = self.inner.last_sequence.replace(seq);
+
+                    if let Some(last_sequence) = last_sequence {
+                        let expected =
+                            (seq as u32 + SEQUENCE_MODULO - last_sequence as u32) % SEQUENCE_MODULO;
+                        if received < expected {
+                            return Some(self.inner.lost(expected - received));
+                        }
+                    }
+                }
+                Some(Packet::Value(v)) => {
+                    if self.inner.pending_values.len() == MAX_PENDING_VALUES {
+                        self.inner.pending_values.remove(0);
+                    }
+                    self.inner.pending_values.push(v);
                 }
+                Some(Packet::Instance(instance)) => {
+                    self.inner.pending_instance = Some(instance);
+                }
+                Some(Packet::Marker { code, delta_t }) => {
+                    let (core, timestamp) = self.inner.advance(delta_t);
+                    if !self.inner.skip_session {
+                        return Some(TimestampedTracepoint::Marker {
+                            timestamp,
+                            core,
+                            code,
+                        });
+                    }
+                }
+                Some(Packet::TaskSwitch { task, delta_t }) => {
+                    let (core, timestamp) = self.inner.advance(delta_t);
+                    if !self.inner.skip_session {
+                        return Some(TimestampedTracepoint::TaskSwitch {
+                            timestamp,
+                            core,
+                            task,
+                        });
+                    }
+                }
+                Some(Packet::TracePoint(tp)) => {
+                    let values = core::mem::take(&mut self.inner.pending_values);
+                    let instance = self.inner.pending_instance.take();
+                    self.inner.received_since_sequence += 1;
+
+                    let (core, timestamp) = self.inner.advance(tp.delta_t);
+                    if self.inner.skip_session {
+                        continue;
+                    }
+
+                    let data = self.inner.metadata.trace_points.get(&tp.id);
+                    if let Some(data) = data {
+                        // Value packets might belong to a trace point which was lost,
+                        // so only the last ones are taken
+                        let value = match data.info.kind {
+                            TracePointKind::Value
+                            | TracePointKind::Counter
+                            | TracePointKind::IsrEnter => values.last().copied(),
+                            TracePointKind::Log => {
+                                return Some(TimestampedTracepoint::Log {
+                                    timestamp,
+                                    core,
+                                    tracepoint: data,
+                                    message: format_message(
+                                        data.info.format.as_deref().unwrap_or_default(),
+                                        &values,
+                                    ),
+                                });
+                            }
+                            TracePointKind::Panic => {
+                                return Some(TimestampedTracepoint::Panic {
+                                    timestamp,
+                                    core,
+                                    tracepoint: data,
+                                    location: panic_location(&values, self.inner.metadata),
+                                });
+                            }
+                            // Calls, suppressed since the previous report
+                            _ if data.info.min_interval_us.is_some() => values.last().copied(),
+                            _ => None,
+                        };
+
+                        return Some(TimestampedTracepoint::Point {
+                            timestamp,
+                            core,
+                            tracepoint: data,
+                            value,
+                            instance,
+                        });
+                    } else {
+                        error!(
+                            "Received trace packet with incorrect id={}. Ignoring.",
+                            tp.id
+                        );
+                    }
+                }
+                None => (),
             }
         }
 
         None
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::memory_dump::MemoryDump;
+    use utrace_core::encoding::{
+        encode, encode_build_id, encode_core, encode_instance, encode_marker, encode_overflow,
+        encode_reset, encode_sequence, encode_task_switch, encode_timestamp, encode_value,
+        TracePoint, SESSION_FLAG_SEQUENCE,
+    };
+    use utrace_core::trace_point::TracePointInfo;
+
+    fn metadata() -> Metadata {
+        Metadata {
+            trace_points: HashMap::from([(1, trace_point(TracePointKind::GenericEnter, "span"))]),
+            build_id: 0x1234,
+            timestamp: Default::default(),
+            rodata: Default::default(),
+        }
+    }
+
+    // Appends the packets, written by the encoder, to the stream
+    fn encode_into(stream: &mut Vec<u8>, encoder: impl FnOnce(&mut dyn FnMut(&[u8]))) {
+        encoder(&mut |b| stream.extend_from_slice(b));
+    }
+
+    fn trace_point(kind: TracePointKind, name: &str) -> TracePointDataWithLocation {
+        TracePointDataWithLocation {
+            info: TracePointInfo {
+                kind,
+                name: Some(name.to_owned()),
+                comment: None,
+                skip: None,
+                id: 1,
+                format: None,
+                min_interval_us: None,
+            },
+            path: None,
+            file_name: None,
+            line: None,
+        }
+    }
+
+    #[test]
+    fn lost_points() {
+        let metadata = metadata();
+        let mut parser = StreamParser::new(&metadata);
+
+        let mut stream = Vec::new();
+        encode_into(&mut stream, |w| {
+            encode_sequence(SEQUENCE_MODULO as u16 - 2, w)
+        });
+        for _ in 0..3 {
+            encode_into(&mut stream, |w| encode(TracePoint { id: 1, delta_t: 1 }, w));
+        }
+        // 5 trace points were emitted, but only 3 of them got through
+        encode_into(&mut stream, |w| encode_sequence(3, w));
+
+        // Sequence counter starts from zero after reset
+        encode_into(&mut stream, |w| encode_reset(SessionHeader::default(), w));
+        encode_into(&mut stream, |w| encode(TracePoint { id: 1, delta_t: 1 }, w));
+        encode_into(&mut stream, |w| encode_sequence(4, w));
+
+        let lost: Vec<_> = parser
+            .push_and_parse(&stream)
+            .filter_map(|p| match p {
+                TimestampedTracepoint::Lost { count } => Some(count),
+                _ => None,
+            })
+            .collect();
+
+        assert_eq!(lost, [2, 3]);
+    }
+
+    #[test]
+    fn overflow() {
+        let metadata = metadata();
+        let mut parser = StreamParser::new(&metadata);
+
+        let mut stream = Vec::new();
+        encode_into(&mut stream, |w| encode_overflow(7, w));
+
+        let parsed: Vec<_> = parser.push_and_parse(&stream).collect();
+
+        assert!(matches!(
+            parsed[..],
+            [TimestampedTracepoint::Lost { count: 7 }]
+        ));
+    }
+
+    #[test]
+    fn values_of_dropped_points() {
+        let mut metadata = metadata();
+        metadata
+            .trace_points
+            .insert(2, trace_point(TracePointKind::IsrEnter, "uart_isr"));
+        let mut parser = StreamParser::new(&metadata);
+
+        let mut stream = Vec::new();
+        // The priority and the instance were accepted, but their trace points were not
+        encode_into(&mut stream, |w| encode_value(Value::U8(3), w));
+        encode_into(&mut stream, |w| encode_overflow(1, w));
+        encode_into(&mut stream, |w| encode(TracePoint { id: 2, delta_t: 1 }, w));
+        encode_into(&mut stream, |w| encode_instance(5, w));
+        encode_into(&mut stream, |w| encode_overflow(1, w));
+        encode_into(&mut stream, |w| encode(TracePoint { id: 1, delta_t: 1 }, w));
+        // Lost on the way from the target
+        encode_into(&mut stream, |w| {
+            encode_sequence(SEQUENCE_MODULO as u16 - 1, w)
+        });
+        encode_into(&mut stream, |w| encode_value(Value::U8(4), w));
+        encode_into(&mut stream, |w| encode_sequence(1, w));
+        encode_into(&mut stream, |w| encode(TracePoint { id: 2, delta_t: 1 }, w));
+
+        let points: Vec<_> = parser
+            .push_and_parse(&stream)
+            .filter_map(|p| match p {
+                TimestampedTracepoint::Point {
+                    value, instance, ..
+                } => Some((value, instance)),
+                _ => None,
+            })
+            .collect();
+
+        assert_eq!(points, [(None, None); 3]);
+    }
+
+    #[test]
+    fn timestamp_resync() {
+        let metadata = metadata();
+        let mut parser = StreamParser::new(&metadata);
+
+        let mut stream = Vec::new();
+        encode_into(&mut stream, |w| encode(TracePoint { id: 1, delta_t: 5 }, w));
+        encode_into(&mut stream, |w| encode_timestamp(1 << 40, w));
+        encode_into(&mut stream, |w| encode(TracePoint { id: 1, delta_t: 0 }, w));
+        encode_into(&mut stream, |w| encode(TracePoint { id: 1, delta_t: 3 }, w));
+
+        let timestamps: Vec<_> = parser
+            .push_and_parse(&stream)
+            .filter_map(|p| match p {
+                TimestampedTracepoint::Point { timestamp, .. } => Some(timestamp),
+                _ => None,
+            })
+            .collect();
+
+        assert_eq!(timestamps, [5, 1 << 40, (1 << 40) + 3]);
+    }
+
+    #[test]
+    fn build_id_mismatch() {
+        let metadata = metadata();
+        let mut parser = StreamParser::new(&metadata);
+
+        let mut stream = Vec::new();
+        encode_into(&mut stream, |w| encode_reset(SessionHeader::default(), w));
+        encode_into(&mut stream, |w| encode_build_id(0x4321, w));
+        encode_into(&mut stream, |w| encode(TracePoint { id: 1, delta_t: 1 }, w));
+        encode_into(&mut stream, |w| encode_reset(SessionHeader::default(), w));
+        encode_into(&mut stream, |w| encode_build_id(0x1234, w));
+        encode_into(&mut stream, |w| encode(TracePoint { id: 1, delta_t: 1 }, w));
+
+        let points = parser
+            .push_and_parse(&stream)
+            .filter(|p| matches!(p, TimestampedTracepoint::Point { .. }))
+            .count();
+
+        assert_eq!(points, 1);
+    }
+
+    #[test]
+    fn per_core_timestamps() {
+        let metadata = metadata();
+        let mut parser = StreamParser::new(&metadata);
+
+        let mut stream = Vec::new();
+        encode_into(&mut stream, |w| encode_reset(SessionHeader::default(), w));
+        for core in 0..2 {
+            encode_into(&mut stream, |w| encode_core(core, w));
+            encode_into(&mut stream, |w| encode_timestamp(100, w));
+        }
+        encode_into(&mut stream, |w| encode(TracePoint { id: 1, delta_t: 5 }, w));
+        encode_into(&mut stream, |w| encode_core(0, w));
+        encode_into(&mut stream, |w| encode(TracePoint { id: 1, delta_t: 2 }, w));
+        encode_into(&mut stream, |w| encode_core(1, w));
+        encode_into(&mut stream, |w| encode(TracePoint { id: 1, delta_t: 1 }, w));
+
+        let points: Vec<_> = parser
+            .push_and_parse(&stream)
+            .filter_map(|p| match p {
+                TimestampedTracepoint::Point {
+                    timestamp, core, ..
+                } => Some((core, timestamp)),
+                _ => None,
+            })
+            .collect();
+
+        assert_eq!(points, [(1, 105), (0, 102), (1, 106)]);
+    }
+
+    #[test]
+    fn protocol_version() {
+        let metadata = metadata();
+        let mut parser = StreamParser::new(&metadata);
+
+        let mut stream = Vec::new();
+        // Firmware with the original encoding
+        encode_into(&mut stream, |w| encode_reset(SessionHeader::LEGACY, w));
+        stream.extend_from_slice(&[0x01, 0x05, 0x80]);
+        // Firmware with a newer encoding
+        encode_into(&mut stream, |w| {
+            encode_reset(
+                SessionHeader {
+                    version: PROTOCOL_VERSION + 1,
+                    ..Default::default()
+                },
+                w,
+            )
+        });
+        encode_into(&mut stream, |w| encode(TracePoint { id: 1, delta_t: 1 }, w));
+
+        let points = parser
+            .push_and_parse(&stream)
+            .filter(|p| matches!(p, TimestampedTracepoint::Point { .. }))
+            .count();
+
+        assert_eq!(points, 1);
+        assert_eq!(
+            parser.session().map(|s| s.version),
+            Some(PROTOCOL_VERSION + 1)
+        );
+    }
+
+    #[test]
+    fn flight_recorder_dump() {
+        let metadata = metadata();
+        let mut parser = StreamParser::new(&metadata);
+
+        let mut stream = Vec::new();
+        encode_into(&mut stream, |w| {
+            encode_reset(
+                SessionHeader {
+                    flags: SESSION_FLAG_SEQUENCE | SESSION_FLAG_FLIGHT_RECORDER,
+                    ..Default::default()
+                },
+                w,
+            )
+        });
+        encode_into(&mut stream, |w| encode_timestamp(1000, w));
+        // The dump starts with the trace points, which were emitted long after init
+        for _ in 0..2 {
+            encode_into(&mut stream, |w| encode(TracePoint { id: 1, delta_t: 1 }, w));
+        }
+        encode_into(&mut stream, |w| encode_sequence(100, w));
+        encode_into(&mut stream, |w| encode(TracePoint { id: 1, delta_t: 1 }, w));
+        encode_into(&mut stream, |w| encode_sequence(102, w));
+
+        let events: Vec<_> = parser
+            .push_and_parse(&stream)
+            .filter_map(|p| match p {
+                TimestampedTracepoint::Point { timestamp, .. } => Some(Ok(timestamp)),
+                TimestampedTracepoint::Lost { count } => Some(Err(count)),
+                _ => None,
+            })
+            .collect();
+
+        assert_eq!(events, vec![Ok(1001), Ok(1002), Ok(1003), Err(1)]);
+    }
+
+    #[test]
+    fn markers_and_task_switches() {
+        let metadata = metadata();
+        let mut parser = StreamParser::new(&metadata);
+
+        let mut stream = Vec::new();
+        encode_into(&mut stream, |w| encode(TracePoint { id: 1, delta_t: 5 }, w));
+        encode_into(&mut stream, |w| encode_marker(42, 2, w));
+        encode_into(&mut stream, |w| encode_task_switch(7, 3, w));
+        encode_into(&mut stream, |w| encode(TracePoint { id: 1, delta_t: 1 }, w));
+
+        let parsed: Vec<_> = parser.push_and_parse(&stream).collect();
+
+        assert!(matches!(
+            parsed[..],
+            [
+                TimestampedTracepoint::Point { timestamp: 5, .. },
+                TimestampedTracepoint::Marker {
+                    timestamp: 7,
+                    code: 42,
+                    ..
+                },
+                TimestampedTracepoint::TaskSwitch {
+                    timestamp: 10,
+                    task: 7,
+                    ..
+                },
+                TimestampedTracepoint::Point { timestamp: 11, .. },
+            ]
+        ));
+    }
+
+    #[test]
+    fn async_instances() {
+        let metadata = metadata();
+        let mut parser = StreamParser::new(&metadata);
+
+        let mut stream = Vec::new();
+        for instance in [3, 4] {
+            encode_into(&mut stream, |w| encode_instance(instance, w));
+            encode_into(&mut stream, |w| encode(TracePoint { id: 1, delta_t: 1 }, w));
+        }
+        encode_into(&mut stream, |w| encode(TracePoint { id: 1, delta_t: 1 }, w));
+
+        let instances: Vec<_> = parser
+            .push_and_parse(&stream)
+            .filter_map(|p| match p {
+                TimestampedTracepoint::Point { instance, .. } => Some(instance),
+                _ => None,
+            })
+            .collect();
+
+        assert_eq!(instances, [Some(3), Some(4), None]);
+    }
+
+    #[test]
+    fn suppressed_calls() {
+        let mut metadata = metadata();
+        let mut sampled = trace_point(TracePointKind::SyncEnter, "sampled");
+        sampled.info.min_interval_us = Some(500);
+        metadata.trace_points.insert(2, sampled);
+        let mut parser = StreamParser::new(&metadata);
+
+        let mut stream = Vec::new();
+        encode_into(&mut stream, |w| encode(TracePoint { id: 2, delta_t: 1 }, w));
+        encode_into(&mut stream, |w| encode_value(Value::U32(17), w));
+        encode_into(&mut stream, |w| {
+            encode(
+                TracePoint {
+                    id: 2,
+                    delta_t: 600,
+                },
+                w,
+            )
+        });
+
+        let values: Vec<_> = parser
+            .push_and_parse(&stream)
+            .filter_map(|p| match p {
+                TimestampedTracepoint::Point { value, .. } => Some(value),
+                _ => None,
+            })
+            .collect();
+
+        assert_eq!(values, [None, Some(Value::U32(17))]);
+    }
+
+    #[test]
+    fn isr_priority() {
+        let mut metadata = metadata();
+        metadata
+            .trace_points
+            .insert(2, trace_point(TracePointKind::IsrEnter, "uart_isr"));
+        let mut parser = StreamParser::new(&metadata);
+
+        let mut stream = Vec::new();
+        encode_into(&mut stream, |w| encode_value(Value::U8(3), w));
+        encode_into(&mut stream, |w| encode(TracePoint { id: 2, delta_t: 1 }, w));
+        encode_into(&mut stream, |w| encode(TracePoint { id: 2, delta_t: 1 }, w));
+
+        let values: Vec<_> = parser
+            .push_and_parse(&stream)
+            .filter_map(|p| match p {
+                TimestampedTracepoint::Point { value, .. } => Some(value),
+                _ => None,
+            })
+            .collect();
+
+        assert_eq!(values, [Some(Value::U8(3)), None]);
+    }
+
+    #[test]
+    fn log_message() {
+        let mut metadata = metadata();
+        let mut log = trace_point(TracePointKind::Log, "log");
+        log.info.format = Some("speed {} rpm, {{{}}}".to_owned());
+        metadata.trace_points.insert(2, log);
+        let mut parser = StreamParser::new(&metadata);
+
+        let mut stream = Vec::new();
+        // Left from a trace point which was lost
+        encode_into(&mut stream, |w| encode_value(Value::U8(1), w));
+        encode_into(&mut stream, |w| encode_value(Value::U32(1500), w));
+        encode_into(&mut stream, |w| encode_value(Value::I32(-3), w));
+        encode_into(&mut stream, |w| encode(TracePoint { id: 2, delta_t: 1 }, w));
+
+        let messages: Vec<_> = parser
+            .push_and_parse(&stream)
+            .filter_map(|p| match p {
+                TimestampedTracepoint::Log { message, .. } => Some(message),
+                _ => None,
+            })
+            .collect();
+
+        assert_eq!(messages, ["speed 1500 rpm, {-3}"]);
+    }
+
+    #[test]
+    fn panic_with_location() {
+        let mut metadata = metadata();
+        metadata
+            .trace_points
+            .insert(2, trace_point(TracePointKind::Panic, "panic"));
+        metadata.rodata = MemoryDump::from_raw(b"..src/main.rs..".to_vec(), 0x1000);
+        let mut parser = StreamParser::new(&metadata);
+
+        let mut stream = Vec::new();
+        for value in [0x1002, 11, 42, 5] {
+            encode_into(&mut stream, |w| encode_value(Value::U32(value), w));
+        }
+        encode_into(&mut stream, |w| encode(TracePoint { id: 2, delta_t: 1 }, w));
+        // File name is outside of the read-only data
+        for value in [0x2000, 11, 42, 5] {
+            encode_into(&mut stream, |w| encode_value(Value::U32(value), w));
+        }
+        encode_into(&mut stream, |w| encode(TracePoint { id: 2, delta_t: 1 }, w));
+
+        let locations: Vec<_> = parser
+            .push_and_parse(&stream)
+            .filter_map(|p| match p {
+                TimestampedTracepoint::Panic { location, .. } => Some(location),
+                _ => None,
+            })
+            .collect();
+
+        assert_eq!(locations, [Some("src/main.rs:42:5".to_owned()), None]);
+    }
+}